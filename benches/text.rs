@@ -0,0 +1,49 @@
+use std::{hint::black_box, sync::Arc};
+
+use aoi::{
+    render::font_options,
+    text::{tree_ellipsis, TextMeasurer},
+};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use tera::Tera;
+
+const TITLE: &str = "Everything In Its Right Place (Live at the Ether Festival, London 2000)";
+const WIDTH: f32 = 476.0;
+// a font that is commonly installed, the tree approach skips text it has no font for
+const FONT_FAMILY: &str = "DejaVu Sans";
+
+fn ellipsis(c: &mut Criterion) {
+    let tera = Tera::new("templates/**/*.html").unwrap();
+    let options = Arc::new(font_options(None));
+
+    let mut group = c.benchmark_group("ellipsis");
+    group.sample_size(10);
+
+    group.bench_function("tree", |b| {
+        b.iter(|| {
+            tree_ellipsis(
+                &tera,
+                &options,
+                black_box(TITLE),
+                WIDTH,
+                FONT_FAMILY,
+                36,
+                "700",
+            )
+        })
+    });
+
+    // a fresh measurer for every run, so widths are shaped rather than cached
+    group.bench_function("measurer", |b| {
+        b.iter_batched(
+            || TextMeasurer::new(options.clone()),
+            |measurer| measurer.ellipsis(black_box(TITLE), WIDTH, FONT_FAMILY, 36, "700"),
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, ellipsis);
+criterion_main!(benches);
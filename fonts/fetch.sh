@@ -0,0 +1,50 @@
+#!/bin/sh
+# Downloads the fonts embedded with the `bundled-fonts` feature.
+# All of them are licensed under the SIL Open Font License 1.1.
+#
+# Downloads are pinned to releases and checked against SHA256SUMS. After
+# bumping a version, run `./fetch.sh --record` to write the new checksums,
+# and review them before committing.
+set -eu
+
+cd "$(dirname "$0")"
+
+INTER_VERSION=4.1
+NOTO_CJK_TAG=Sans2.004
+NOTO_EMOJI_TAG=v2.047
+
+INTER=https://github.com/rsms/inter/releases/download/v$INTER_VERSION
+NOTO_CJK=https://raw.githubusercontent.com/notofonts/noto-cjk/$NOTO_CJK_TAG/Sans/SubsetOTF
+NOTO_EMOJI=https://raw.githubusercontent.com/googlefonts/noto-emoji/$NOTO_EMOJI_TAG/fonts
+
+RECORD=false
+if [ "${1:-}" = "--record" ]; then
+    RECORD=true
+    : > SHA256SUMS
+elif [ ! -f SHA256SUMS ]; then
+    echo "SHA256SUMS is missing, run with --record to write it" >&2
+    exit 1
+fi
+
+# Downloads $2 to $1, then checks it against its line in SHA256SUMS
+fetch() {
+    curl -fsSL -o "$1" "$2"
+
+    if $RECORD; then
+        sha256sum "$1" >> SHA256SUMS
+    elif ! grep "  $1\$" SHA256SUMS | sha256sum -c --quiet -; then
+        echo "Checksum mismatch or missing for $1" >&2
+        rm -f "$1"
+        exit 1
+    fi
+}
+
+fetch inter.zip "$INTER/Inter-$INTER_VERSION.zip"
+unzip -jo inter.zip extras/ttf/Inter-Regular.ttf extras/ttf/Inter-Bold.ttf
+rm inter.zip
+
+for font in JP/NotoSansJP-Regular JP/NotoSansJP-Bold KR/NotoSansKR-Regular KR/NotoSansKR-Bold; do
+    fetch "$(basename "$font").otf" "$NOTO_CJK/$font.otf"
+done
+
+fetch NotoColorEmoji.ttf "$NOTO_EMOJI/NotoColorEmoji.ttf"
@@ -0,0 +1,158 @@
+use std::{collections::HashMap, env, fs};
+
+use log::info;
+use serde::Deserialize;
+
+use crate::{
+    cover::{
+        COVER_ART_ARCHIVE_URL, DEEZER_URL, DEFAULT_PROVIDERS, DISCOGS_URL, ITUNES_URL, SPOTIFY_URL,
+    },
+    listenbrainz::{API_ROOT_URL, DEFAULT_TIMEOUT},
+};
+
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    pub listenbrainz: ListenBrainzConfig,
+    pub lastfm: LastFmConfig,
+    pub subsonic: Option<SubsonicConfig>,
+    pub fonts: FontsConfig,
+    pub themes: ThemesConfig,
+    pub cover_art: CoverArtConfig,
+    /// ListenBrainz-compatible servers reachable under `/i/{name}`, only
+    /// instances listed here can be queried
+    pub instances: HashMap<String, ListenBrainzConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ListenBrainzConfig {
+    pub api_root_url: String,
+    /// Seconds after which a single API call is abandoned
+    pub timeout: u64,
+}
+
+impl Default for ListenBrainzConfig {
+    fn default() -> Self {
+        Self {
+            api_root_url: API_ROOT_URL.to_string(),
+            timeout: DEFAULT_TIMEOUT.as_secs(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct LastFmConfig {
+    pub api_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SubsonicConfig {
+    pub url: String,
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct FontsConfig {
+    /// Directory of additional font files, loaded on top of the system fonts
+    pub directory: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ThemesConfig {
+    /// Directory of `{name}.toml` themes, selected with `theme={name}`
+    pub directory: String,
+}
+
+impl Default for ThemesConfig {
+    fn default() -> Self {
+        Self {
+            directory: "themes".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct CoverArtConfig {
+    /// Providers tried in order until one has the cover of a listen
+    pub providers: Vec<String>,
+    /// Seconds after which a single lookup is abandoned
+    pub timeout: u64,
+    pub cover_art_archive_url: String,
+    pub spotify_url: String,
+    pub itunes_url: String,
+    pub deezer_url: String,
+    pub discogs_url: String,
+    /// Personal access token, Discogs is skipped without one
+    pub discogs_token: Option<String>,
+    /// Directory of images named after the release, the release group or
+    /// `{artist} - {album}`, skipped when unset
+    pub directory: Option<String>,
+}
+
+impl Default for CoverArtConfig {
+    fn default() -> Self {
+        Self {
+            providers: DEFAULT_PROVIDERS
+                .iter()
+                .map(|val| val.to_string())
+                .collect(),
+            timeout: DEFAULT_TIMEOUT.as_secs(),
+            cover_art_archive_url: COVER_ART_ARCHIVE_URL.to_string(),
+            spotify_url: SPOTIFY_URL.to_string(),
+            itunes_url: ITUNES_URL.to_string(),
+            deezer_url: DEEZER_URL.to_string(),
+            discogs_url: DISCOGS_URL.to_string(),
+            discogs_token: None,
+            directory: None,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file at `AOI_CONFIG`, or `config.toml` when unset.
+    /// A missing default file results in the default config.
+    pub fn load() -> Result<Config, String> {
+        let path = env::var("AOI_CONFIG").ok();
+
+        let mut config = match fs::read_to_string(path.as_deref().unwrap_or(DEFAULT_CONFIG_PATH)) {
+            Ok(val) => match toml::from_str::<Config>(&val) {
+                Ok(val) => val,
+                Err(err) => return Err(format!("Error while parsing config: {}", err)),
+            },
+            Err(err) => match path {
+                Some(path) => return Err(format!("Cannot read config [{}]: {}", path, err)),
+                None => {
+                    info!("No config file found, using defaults");
+                    Config::default()
+                }
+            },
+        };
+
+        if let Some(name) = config.instances.keys().find(|name| {
+            name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        }) {
+            return Err(format!("Invalid instance name [{}]", name));
+        }
+
+        if config.lastfm.api_key.is_none() {
+            config.lastfm.api_key = env::var("LASTFM_API_KEY").ok();
+        }
+
+        if config.cover_art.discogs_token.is_none() {
+            config.cover_art.discogs_token = env::var("DISCOGS_TOKEN").ok();
+        }
+
+        Ok(config)
+    }
+}
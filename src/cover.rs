@@ -0,0 +1,1016 @@
+use std::{future::Future, path::PathBuf, sync::LazyLock, time::Duration};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use moka::future::Cache;
+use regex::Regex;
+use reqwest::{RequestBuilder, Url};
+use serde::Deserialize;
+
+use crate::{
+    config::CoverArtConfig,
+    error::AoiError,
+    playing::{release_group_id_by_release, ListenData},
+};
+
+pub const COVER_ART_ARCHIVE_URL: &str = "https://coverartarchive.org/";
+pub const SPOTIFY_URL: &str = "https://open.spotify.com/";
+pub const ITUNES_URL: &str = "https://itunes.apple.com/";
+pub const DEEZER_URL: &str = "https://api.deezer.com/";
+pub const DISCOGS_URL: &str = "https://api.discogs.com/";
+
+/// Providers tried in order when the config does not list any.
+pub const DEFAULT_PROVIDERS: &[&str] = &[
+    "release",
+    "release-group",
+    "spotify",
+    "url",
+    "itunes",
+    "deezer",
+    "discogs",
+    "local",
+];
+
+/// How long a failed lookup is remembered before the provider is asked again.
+pub const MISS_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// Discogs rejects requests without a user agent.
+const USER_AGENT: &str = concat!("aoi/", env!("CARGO_PKG_VERSION"));
+
+/// Looks up the cover art of listens on a single service.
+#[async_trait]
+pub trait CoverArtProvider: Send + Sync {
+    /// Name of the provider in the config, also prefixing its cache keys.
+    fn key(&self) -> &str;
+
+    /// Identifies the cover of a listen, `None` when the listen lacks the
+    /// metadata this provider looks up.
+    fn cover_key(&self, listen: &ListenData) -> Option<String>;
+
+    /// Returns the image data of the cover of a listen.
+    async fn cover_art(&self, listen: &ListenData) -> Result<Vec<u8>, AoiError>;
+}
+
+/// Image URL of the embed player data, at the size the player shows.
+static SPOTIFY_IMAGE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(https?://[^"]+)","(?:maxHeight|maxWidth)":300"#).unwrap());
+
+/// Cover art providers tried in order, remembering failed lookups for
+/// `MISS_TTL` so that listens without a cover do not hit every provider on
+/// each render.
+pub struct CoverArtChain {
+    providers: Vec<Box<dyn CoverArtProvider>>,
+    misses: Cache<String, ()>,
+}
+
+impl CoverArtChain {
+    pub fn new(providers: Vec<Box<dyn CoverArtProvider>>, miss_ttl: Duration) -> Self {
+        Self {
+            providers,
+            misses: Cache::builder().time_to_live(miss_ttl).build(),
+        }
+    }
+
+    /// Builds the providers listed in the config, in order. Discogs without a
+    /// token and local covers without a directory are left out.
+    pub fn from_config(config: &CoverArtConfig) -> Result<CoverArtChain, String> {
+        Ok(CoverArtChain::new(cover_art_providers(config)?, MISS_TTL))
+    }
+
+    /// Names of the providers, in lookup order.
+    pub fn keys(&self) -> Vec<&str> {
+        self.providers
+            .iter()
+            .map(|provider| provider.key())
+            .collect()
+    }
+
+    /// Runs `fetch` unless the lookup identified by `key` recently failed,
+    /// remembering it when it fails again.
+    pub async fn fetch<T>(
+        &self,
+        key: String,
+        fetch: impl Future<Output = Result<T, AoiError>>,
+    ) -> Option<T> {
+        if self.misses.contains_key(&key) {
+            return None;
+        }
+
+        match fetch.await {
+            Ok(val) => Some(val),
+            Err(err) => {
+                warn!("Cannot get cover art [{}]: {}", &key, err);
+                self.misses.insert(key, ()).await;
+                None
+            }
+        }
+    }
+
+    /// Asks each provider having metadata to look up in order, until one has
+    /// the cover. `fetch` is given the `{provider}-{cover key}` identifying
+    /// the lookup, for caching.
+    pub async fn lookup<'a, T, F, Fut>(&'a self, listen: &ListenData, mut fetch: F) -> Option<T>
+    where
+        F: FnMut(String, &'a dyn CoverArtProvider) -> Fut,
+        Fut: Future<Output = Result<T, AoiError>>,
+    {
+        for provider in &self.providers {
+            let key = match provider.cover_key(listen) {
+                Some(val) => format!("{}-{}", provider.key(), val),
+                None => continue,
+            };
+
+            if let Some(val) = self.fetch(key.clone(), fetch(key, provider.as_ref())).await {
+                return Some(val);
+            }
+        }
+
+        None
+    }
+}
+
+fn cover_art_providers(config: &CoverArtConfig) -> Result<Vec<Box<dyn CoverArtProvider>>, String> {
+    let http = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.timeout))
+        .user_agent(USER_AGENT)
+        .build()
+    {
+        Ok(val) => val,
+        Err(err) => return Err(format!("Cannot build HTTP client: {}", err)),
+    };
+
+    let mut providers: Vec<Box<dyn CoverArtProvider>> = Vec::new();
+
+    for name in &config.providers {
+        match name.as_str() {
+            "release" | "release-group" => providers.push(Box::new(CoverArtArchive {
+                key: name.clone(),
+                http: http.clone(),
+                url: config.cover_art_archive_url.clone(),
+            })),
+            "spotify" => providers.push(Box::new(Spotify {
+                http: http.clone(),
+                url: config.spotify_url.clone(),
+            })),
+            "url" => providers.push(Box::new(ImageUrl { http: http.clone() })),
+            "itunes" => providers.push(Box::new(ITunes {
+                http: http.clone(),
+                url: config.itunes_url.clone(),
+            })),
+            "deezer" => providers.push(Box::new(Deezer {
+                http: http.clone(),
+                url: config.deezer_url.clone(),
+            })),
+            "discogs" => match &config.discogs_token {
+                Some(token) => providers.push(Box::new(Discogs {
+                    http: http.clone(),
+                    url: config.discogs_url.clone(),
+                    token: token.clone(),
+                })),
+                None => info!("Discogs token is not set, Discogs covers are disabled"),
+            },
+            "local" => match &config.directory {
+                Some(directory) => providers.push(Box::new(LocalDirectory {
+                    directory: PathBuf::from(directory),
+                })),
+                None => info!("Cover art directory is not set, local covers are disabled"),
+            },
+            _ => return Err(format!("Unknown cover art provider [{}]", name)),
+        }
+    }
+
+    info!(
+        "Looking up cover art from {}",
+        providers
+            .iter()
+            .map(|provider| provider.key())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    );
+
+    Ok(providers)
+}
+
+/// Artist and album name of a listen, for providers searching by name.
+fn artist_album(listen: &ListenData) -> Option<(&str, &str)> {
+    let album = listen.metadata.as_ref()?.release_name.as_deref()?;
+
+    match listen.artist.is_empty() || album.is_empty() {
+        true => None,
+        false => Some((&listen.artist, album)),
+    }
+}
+
+/// Joins `path` to a configured base URL, percent-encoding each segment.
+fn endpoint(base_url: &str, path: &[&str]) -> Result<Url, AoiError> {
+    let mut url = match Url::parse(base_url) {
+        Ok(val) => val,
+        Err(err) => {
+            return Err(AoiError::Upstream(format!(
+                "Invalid cover art URL [{}]: {}",
+                base_url, err
+            )))
+        }
+    };
+
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments.pop_if_empty().extend(path);
+    }
+
+    Ok(url)
+}
+
+async fn get_bytes(request: RequestBuilder, context: &str) -> Result<Vec<u8>, AoiError> {
+    let response = match request.send().await.and_then(|val| val.error_for_status()) {
+        Ok(val) => val,
+        Err(err) => return Err(AoiError::from_reqwest(context, err)),
+    };
+
+    match response.bytes().await {
+        Ok(val) => Ok(val.to_vec()),
+        Err(err) => Err(AoiError::from_reqwest(
+            "Error while parsing image bytes",
+            err,
+        )),
+    }
+}
+
+async fn get_json<R: for<'de> Deserialize<'de>>(
+    request: RequestBuilder,
+    context: &str,
+) -> Result<R, AoiError> {
+    let response = match request.send().await.and_then(|val| val.error_for_status()) {
+        Ok(val) => val,
+        Err(err) => return Err(AoiError::from_reqwest(context, err)),
+    };
+
+    match response.json::<R>().await {
+        Ok(val) => Ok(val),
+        Err(err) => Err(AoiError::from_reqwest(context, err)),
+    }
+}
+
+/// Downloads the first image of a search, failing when nothing was found.
+async fn first_image(
+    http: &reqwest::Client,
+    image: Option<String>,
+    service: &str,
+) -> Result<Vec<u8>, AoiError> {
+    match image.filter(|val| !val.is_empty()) {
+        Some(url) => get_bytes(http.get(url), "Error while getting image data").await,
+        None => Err(AoiError::NotFound(format!(
+            "No cover art found on {}",
+            service
+        ))),
+    }
+}
+
+/// Front covers of the Cover Art Archive, by release or release group. The
+/// release group is looked up from the release when the listen does not have
+/// it, so the lookup only happens once the release has no cover.
+struct CoverArtArchive {
+    /// `release` or `release-group`, also the path of the API
+    key: String,
+    http: reqwest::Client,
+    url: String,
+}
+
+#[async_trait]
+impl CoverArtProvider for CoverArtArchive {
+    fn key(&self) -> &str {
+        &self.key
+    }
+
+    fn cover_key(&self, listen: &ListenData) -> Option<String> {
+        let metadata = listen.metadata.as_ref()?;
+
+        match self.key.as_str() {
+            "release" => metadata.release.clone(),
+            _ => metadata.release_group.clone().or(metadata.release.clone()),
+        }
+    }
+
+    async fn cover_art(&self, listen: &ListenData) -> Result<Vec<u8>, AoiError> {
+        let metadata = listen.metadata.as_ref();
+        let id = match self.key.as_str() {
+            "release" => metadata.and_then(|metadata| metadata.release.clone()),
+            _ => match metadata.and_then(|metadata| metadata.release_group.clone()) {
+                Some(val) => Some(val),
+                None => match metadata.and_then(|metadata| metadata.release.as_ref()) {
+                    Some(release_id) => Some(release_group_id_by_release(release_id).await?),
+                    None => None,
+                },
+            },
+        };
+
+        let id = match id {
+            Some(val) => val,
+            None => return Err(AoiError::NotFound(format!("No {} to look up", self.key))),
+        };
+
+        // large enough for retina output, covers are scaled down before caching
+        let url = endpoint(&self.url, &[&self.key, &id, "front-500"])?;
+
+        get_bytes(self.http.get(url), "Error while getting image data").await
+    }
+}
+
+/// Album art scraped from the Spotify embed player.
+struct Spotify {
+    http: reqwest::Client,
+    url: String,
+}
+
+#[async_trait]
+impl CoverArtProvider for Spotify {
+    fn key(&self) -> &str {
+        "spotify"
+    }
+
+    fn cover_key(&self, listen: &ListenData) -> Option<String> {
+        listen.metadata.as_ref()?.spotify_path.clone()
+    }
+
+    async fn cover_art(&self, listen: &ListenData) -> Result<Vec<u8>, AoiError> {
+        let path = match self.cover_key(listen) {
+            Some(val) => val,
+            None => {
+                return Err(AoiError::NotFound(
+                    "No Spotify album to look up".to_string(),
+                ))
+            }
+        };
+
+        let mut segments = vec!["embed"];
+        segments.extend(path.split('/'));
+        let url = endpoint(&self.url, &segments)?;
+
+        let response = match self.http.get(url).send().await {
+            Ok(val) => val,
+            Err(err) => {
+                return Err(AoiError::from_reqwest(
+                    "Error while getting Spotify data",
+                    err,
+                ))
+            }
+        };
+
+        let data = match response.text().await {
+            Ok(val) => val,
+            Err(err) => {
+                return Err(AoiError::from_reqwest(
+                    "Error while parsing Spotify data",
+                    err,
+                ))
+            }
+        };
+
+        let url = SPOTIFY_IMAGE
+            .captures(&data)
+            .map(|captures| captures[1].to_string());
+
+        first_image(&self.http, url, "Spotify").await
+    }
+}
+
+/// Image URL given by the listen source, such as Last.fm.
+struct ImageUrl {
+    http: reqwest::Client,
+}
+
+#[async_trait]
+impl CoverArtProvider for ImageUrl {
+    fn key(&self) -> &str {
+        "url"
+    }
+
+    fn cover_key(&self, listen: &ListenData) -> Option<String> {
+        listen.metadata.as_ref()?.image_url.clone()
+    }
+
+    async fn cover_art(&self, listen: &ListenData) -> Result<Vec<u8>, AoiError> {
+        first_image(&self.http, self.cover_key(listen), "the listen source").await
+    }
+}
+
+#[derive(Deserialize)]
+struct ITunesResponse {
+    #[serde(default)]
+    results: Vec<ITunesAlbum>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ITunesAlbum {
+    artwork_url100: Option<String>,
+}
+
+/// Album search of the iTunes Search API.
+struct ITunes {
+    http: reqwest::Client,
+    url: String,
+}
+
+#[async_trait]
+impl CoverArtProvider for ITunes {
+    fn key(&self) -> &str {
+        "itunes"
+    }
+
+    fn cover_key(&self, listen: &ListenData) -> Option<String> {
+        artist_album(listen).map(|(artist, album)| format!("{} - {}", artist, album))
+    }
+
+    async fn cover_art(&self, listen: &ListenData) -> Result<Vec<u8>, AoiError> {
+        let (artist, album) = match artist_album(listen) {
+            Some(val) => val,
+            None => return Err(AoiError::NotFound("No album to search".to_string())),
+        };
+
+        let request = self.http.get(endpoint(&self.url, &["search"])?).query(&[
+            ("term", format!("{} {}", artist, album).as_str()),
+            ("entity", "album"),
+            ("limit", "1"),
+        ]);
+        let response: ITunesResponse = get_json(request, "Error while searching iTunes").await?;
+
+        // artwork is served at any size, the search only links the smallest
+        let image = response
+            .results
+            .into_iter()
+            .find_map(|album| album.artwork_url100)
+            .map(|url| url.replace("100x100bb", "600x600bb"));
+
+        first_image(&self.http, image, "iTunes").await
+    }
+}
+
+#[derive(Deserialize)]
+struct DeezerResponse {
+    #[serde(default)]
+    data: Vec<DeezerAlbum>,
+}
+
+#[derive(Deserialize)]
+struct DeezerAlbum {
+    cover_big: Option<String>,
+}
+
+/// Album search of the Deezer API.
+struct Deezer {
+    http: reqwest::Client,
+    url: String,
+}
+
+#[async_trait]
+impl CoverArtProvider for Deezer {
+    fn key(&self) -> &str {
+        "deezer"
+    }
+
+    fn cover_key(&self, listen: &ListenData) -> Option<String> {
+        artist_album(listen).map(|(artist, album)| format!("{} - {}", artist, album))
+    }
+
+    async fn cover_art(&self, listen: &ListenData) -> Result<Vec<u8>, AoiError> {
+        let (artist, album) = match artist_album(listen) {
+            Some(val) => val,
+            None => return Err(AoiError::NotFound("No album to search".to_string())),
+        };
+
+        let query = format!(
+            "artist:\"{}\" album:\"{}\"",
+            artist.replace('"', ""),
+            album.replace('"', "")
+        );
+        let request = self
+            .http
+            .get(endpoint(&self.url, &["search", "album"])?)
+            .query(&[("q", query.as_str()), ("limit", "1")]);
+        let response: DeezerResponse = get_json(request, "Error while searching Deezer").await?;
+
+        let image = response.data.into_iter().find_map(|album| album.cover_big);
+
+        first_image(&self.http, image, "Deezer").await
+    }
+}
+
+#[derive(Deserialize)]
+struct DiscogsResponse {
+    #[serde(default)]
+    results: Vec<DiscogsRelease>,
+}
+
+#[derive(Deserialize)]
+struct DiscogsRelease {
+    cover_image: Option<String>,
+}
+
+/// Release search of the Discogs API, which requires a personal token.
+struct Discogs {
+    http: reqwest::Client,
+    url: String,
+    token: String,
+}
+
+#[async_trait]
+impl CoverArtProvider for Discogs {
+    fn key(&self) -> &str {
+        "discogs"
+    }
+
+    fn cover_key(&self, listen: &ListenData) -> Option<String> {
+        artist_album(listen).map(|(artist, album)| format!("{} - {}", artist, album))
+    }
+
+    async fn cover_art(&self, listen: &ListenData) -> Result<Vec<u8>, AoiError> {
+        let (artist, album) = match artist_album(listen) {
+            Some(val) => val,
+            None => return Err(AoiError::NotFound("No album to search".to_string())),
+        };
+
+        let request = self
+            .http
+            .get(endpoint(&self.url, &["database", "search"])?)
+            .header("Authorization", format!("Discogs token={}", self.token))
+            .query(&[
+                ("type", "release"),
+                ("artist", artist),
+                ("release_title", album),
+                ("per_page", "1"),
+            ]);
+        let response: DiscogsResponse = get_json(request, "Error while searching Discogs").await?;
+
+        let image = response
+            .results
+            .into_iter()
+            .find_map(|release| release.cover_image);
+
+        first_image(&self.http, image, "Discogs").await
+    }
+}
+
+/// Image files of a local directory, named after the release, the release
+/// group or `{artist} - {album}`.
+struct LocalDirectory {
+    directory: PathBuf,
+}
+
+impl LocalDirectory {
+    const EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp"];
+
+    /// File names looked up for a listen, in order, without their extension.
+    fn names(listen: &ListenData) -> Vec<String> {
+        let metadata = listen.metadata.as_ref();
+
+        [
+            metadata.and_then(|metadata| metadata.release.clone()),
+            metadata.and_then(|metadata| metadata.release_group.clone()),
+            artist_album(listen).map(|(artist, album)| format!("{} - {}", artist, album)),
+        ]
+        .into_iter()
+        .flatten()
+        // names cannot leave the directory
+        .map(|name| {
+            name.replace(['/', '\\', '\0'], "_")
+                .trim_start_matches('.')
+                .to_string()
+        })
+        .filter(|name| !name.is_empty())
+        .collect()
+    }
+}
+
+#[async_trait]
+impl CoverArtProvider for LocalDirectory {
+    fn key(&self) -> &str {
+        "local"
+    }
+
+    fn cover_key(&self, listen: &ListenData) -> Option<String> {
+        let names = LocalDirectory::names(listen);
+
+        match names.is_empty() {
+            true => None,
+            false => Some(names.join(", ")),
+        }
+    }
+
+    async fn cover_art(&self, listen: &ListenData) -> Result<Vec<u8>, AoiError> {
+        for name in LocalDirectory::names(listen) {
+            for extension in LocalDirectory::EXTENSIONS {
+                let path = self.directory.join(format!("{}.{}", name, extension));
+
+                if let Ok(data) = tokio::fs::read(&path).await {
+                    return Ok(data);
+                }
+            }
+        }
+
+        Err(AoiError::NotFound(format!(
+            "No cover art found in [{}]",
+            self.directory.display()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        extract::Query,
+        http::{HeaderMap, StatusCode},
+        response::IntoResponse,
+        routing::get,
+        Json, Router,
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        mock::serve,
+        playing::{ListenMetadata, RELEASE_GROUPS},
+    };
+
+    const IMAGE: &[u8] = b"image data";
+
+    fn listen(metadata: ListenMetadata) -> ListenData {
+        ListenData {
+            title: "Title".to_string(),
+            artist: "Artist".to_string(),
+            listened_at: None,
+            metadata: Some(metadata),
+        }
+    }
+
+    fn metadata() -> ListenMetadata {
+        ListenMetadata {
+            release: Some("release-id".to_string()),
+            release_name: Some("Album".to_string()),
+            release_group: Some("group-id".to_string()),
+            spotify_path: Some("album/spotify-id".to_string()),
+            image_url: None,
+            cover_art_id: None,
+        }
+    }
+
+    fn host(headers: &HeaderMap) -> String {
+        format!("http://{}", headers["host"].to_str().unwrap())
+    }
+
+    /// Mock of every service, answering only the requests a provider should
+    /// send for `metadata()`.
+    async fn mock() -> String {
+        let router = Router::new()
+            .route("/release/release-id/front-500", get(|| async { IMAGE }))
+            .route("/release-group/group-id/front-500", get(|| async { IMAGE }))
+            .route("/image.jpg", get(|| async { IMAGE }))
+            .route("/600x600bb.jpg", get(|| async { IMAGE }))
+            .route(
+                "/embed/album/spotify-id",
+                get(|headers: HeaderMap| async move {
+                    format!(
+                        r#"<script>{{"url":"{}/image.jpg","maxHeight":300}}</script>"#,
+                        host(&headers)
+                    )
+                }),
+            )
+            .route(
+                "/search",
+                get(
+                    |headers: HeaderMap, Query(query): Query<HashMap<String, String>>| async move {
+                        match query["term"] == "Artist Album" && query["entity"] == "album" {
+                            true => Json(json!({ "results": [
+                                { "artworkUrl100": format!("{}/100x100bb.jpg", host(&headers)) }
+                            ] })),
+                            false => Json(json!({ "results": [] })),
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/search/album",
+                get(
+                    |headers: HeaderMap, Query(query): Query<HashMap<String, String>>| async move {
+                        match query["q"] == r#"artist:"Artist" album:"Album""# {
+                            true => Json(json!({ "data": [
+                                { "cover_big": format!("{}/image.jpg", host(&headers)) }
+                            ] })),
+                            false => Json(json!({ "data": [] })),
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/database/search",
+                get(
+                    |headers: HeaderMap, Query(query): Query<HashMap<String, String>>| async move {
+                        if headers["authorization"] != "Discogs token=token" {
+                            return StatusCode::UNAUTHORIZED.into_response();
+                        }
+
+                        match query["artist"] == "Artist" && query["release_title"] == "Album" {
+                            true => Json(json!({ "results": [
+                                { "cover_image": format!("{}/image.jpg", host(&headers)) }
+                            ] }))
+                            .into_response(),
+                            false => Json(json!({ "results": [] })).into_response(),
+                        }
+                    },
+                ),
+            );
+
+        serve(router).await
+    }
+
+    fn config(url: &str, providers: &[&str]) -> CoverArtConfig {
+        CoverArtConfig {
+            providers: providers.iter().map(|val| val.to_string()).collect(),
+            timeout: 5,
+            cover_art_archive_url: url.to_string(),
+            spotify_url: url.to_string(),
+            itunes_url: url.to_string(),
+            deezer_url: url.to_string(),
+            discogs_url: url.to_string(),
+            discogs_token: Some("token".to_string()),
+            directory: None,
+        }
+    }
+
+    async fn provider(name: &str) -> Box<dyn CoverArtProvider> {
+        let url = mock().await;
+
+        cover_art_providers(&config(&url, &[name]))
+            .unwrap()
+            .remove(0)
+    }
+
+    #[tokio::test]
+    async fn cover_art_archive_release() {
+        let provider = provider("release").await;
+
+        assert_eq!(
+            provider.cover_key(&listen(metadata())).unwrap(),
+            "release-id"
+        );
+        assert_eq!(
+            provider.cover_art(&listen(metadata())).await.unwrap(),
+            IMAGE
+        );
+    }
+
+    #[tokio::test]
+    async fn cover_art_archive_release_group() {
+        let provider = provider("release-group").await;
+
+        assert_eq!(provider.cover_key(&listen(metadata())).unwrap(), "group-id");
+        assert_eq!(
+            provider.cover_art(&listen(metadata())).await.unwrap(),
+            IMAGE
+        );
+
+        let missing = listen(ListenMetadata {
+            release_group: Some("other".to_string()),
+            ..metadata()
+        });
+        assert!(matches!(
+            provider.cover_art(&missing).await,
+            Err(AoiError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn cover_art_archive_release_group_from_release() {
+        let provider = provider("release-group").await;
+        let listen = listen(ListenMetadata {
+            release: Some("known-release".to_string()),
+            release_group: None,
+            ..metadata()
+        });
+
+        // already looked up, so MusicBrainz is not asked
+        RELEASE_GROUPS
+            .insert("known-release".to_string(), "group-id".to_string())
+            .await;
+
+        assert_eq!(provider.cover_key(&listen).unwrap(), "known-release");
+        assert_eq!(provider.cover_art(&listen).await.unwrap(), IMAGE);
+    }
+
+    #[tokio::test]
+    async fn spotify() {
+        let provider = provider("spotify").await;
+
+        assert_eq!(
+            provider.cover_art(&listen(metadata())).await.unwrap(),
+            IMAGE
+        );
+    }
+
+    #[tokio::test]
+    async fn image_url() {
+        let url = mock().await;
+        let provider = cover_art_providers(&config(&url, &["url"]))
+            .unwrap()
+            .remove(0);
+
+        assert!(provider.cover_key(&listen(metadata())).is_none());
+
+        let with_url = listen(ListenMetadata {
+            image_url: Some(format!("{}image.jpg", url)),
+            ..metadata()
+        });
+        assert_eq!(provider.cover_art(&with_url).await.unwrap(), IMAGE);
+    }
+
+    #[tokio::test]
+    async fn itunes() {
+        let provider = provider("itunes").await;
+
+        assert_eq!(
+            provider.cover_key(&listen(metadata())).unwrap(),
+            "Artist - Album"
+        );
+        assert_eq!(
+            provider.cover_art(&listen(metadata())).await.unwrap(),
+            IMAGE
+        );
+    }
+
+    #[tokio::test]
+    async fn deezer() {
+        let provider = provider("deezer").await;
+
+        assert_eq!(
+            provider.cover_art(&listen(metadata())).await.unwrap(),
+            IMAGE
+        );
+
+        let unknown = listen(ListenMetadata {
+            release_name: Some("Unknown".to_string()),
+            ..metadata()
+        });
+        assert!(matches!(
+            provider.cover_art(&unknown).await,
+            Err(AoiError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn discogs() {
+        let provider = provider("discogs").await;
+
+        assert_eq!(
+            provider.cover_art(&listen(metadata())).await.unwrap(),
+            IMAGE
+        );
+
+        let without_token = CoverArtConfig {
+            discogs_token: None,
+            ..config("http://127.0.0.1/", &["discogs"])
+        };
+        assert!(cover_art_providers(&without_token).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn local_directory() {
+        let directory = std::env::temp_dir().join(format!("aoi-covers-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("Artist - Album.png"), b"by name").unwrap();
+        std::fs::write(directory.join("group-id.jpg"), b"by group").unwrap();
+
+        let config = CoverArtConfig {
+            directory: Some(directory.to_string_lossy().to_string()),
+            ..config("http://127.0.0.1/", &["local"])
+        };
+        let provider = cover_art_providers(&config).unwrap().remove(0);
+
+        // the release has no file, so the release group comes next
+        assert_eq!(
+            provider.cover_art(&listen(metadata())).await.unwrap(),
+            b"by group"
+        );
+
+        let by_name = listen(ListenMetadata {
+            release: None,
+            release_group: None,
+            ..metadata()
+        });
+        assert_eq!(provider.cover_art(&by_name).await.unwrap(), b"by name");
+
+        // names cannot point outside of the directory
+        let escaping = listen(ListenMetadata {
+            release: Some("../Artist - Album".to_string()),
+            release_group: None,
+            release_name: None,
+            ..metadata()
+        });
+        assert!(provider.cover_art(&escaping).await.is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn unknown_provider_is_rejected() {
+        let result =
+            CoverArtChain::from_config(&config("http://127.0.0.1/", &["release", "lyrics"]));
+
+        assert_eq!(result.err().unwrap(), "Unknown cover art provider [lyrics]");
+    }
+
+    #[test]
+    fn providers_follow_config_order() {
+        let chain =
+            CoverArtChain::from_config(&config("http://127.0.0.1/", &["deezer", "release", "url"]))
+                .unwrap();
+
+        assert_eq!(chain.keys(), ["deezer", "release", "url"]);
+    }
+
+    /// Records its lookups, answering with `result` for listens it has a key for.
+    struct Fake {
+        key: &'static str,
+        has_key: bool,
+        result: Option<&'static [u8]>,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait]
+    impl CoverArtProvider for Fake {
+        fn key(&self) -> &str {
+            self.key
+        }
+
+        fn cover_key(&self, _listen: &ListenData) -> Option<String> {
+            self.has_key.then(|| "id".to_string())
+        }
+
+        async fn cover_art(&self, _listen: &ListenData) -> Result<Vec<u8>, AoiError> {
+            self.calls.lock().unwrap().push(self.key);
+
+            match self.result {
+                Some(val) => Ok(val.to_vec()),
+                None => Err(AoiError::NotFound("No cover".to_string())),
+            }
+        }
+    }
+
+    fn fake_chain(calls: &Arc<Mutex<Vec<&'static str>>>) -> CoverArtChain {
+        let fake = |key, has_key, result| -> Box<dyn CoverArtProvider> {
+            Box::new(Fake {
+                key,
+                has_key,
+                result,
+                calls: calls.clone(),
+            })
+        };
+
+        CoverArtChain::new(
+            vec![
+                fake("no-metadata", false, Some(b"skipped")),
+                fake("failing", true, None),
+                fake("found", true, Some(b"found")),
+                fake("last", true, Some(b"last")),
+            ],
+            Duration::from_secs(60),
+        )
+    }
+
+    #[tokio::test]
+    async fn chain_stops_at_first_cover() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let chain = fake_chain(&calls);
+
+        let result = chain
+            .lookup(&listen(metadata()), |key, provider| async move {
+                provider
+                    .cover_art(&listen(metadata()))
+                    .await
+                    .map(|data| (key, data))
+            })
+            .await;
+
+        assert_eq!(result, Some(("found-id".to_string(), b"found".to_vec())));
+        assert_eq!(*calls.lock().unwrap(), ["failing", "found"]);
+    }
+
+    #[tokio::test]
+    async fn chain_remembers_misses() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let chain = fake_chain(&calls);
+
+        for _ in 0..2 {
+            chain
+                .lookup(&listen(metadata()), |_, provider| async move {
+                    provider.cover_art(&listen(metadata())).await
+                })
+                .await;
+        }
+
+        assert_eq!(*calls.lock().unwrap(), ["failing", "found", "found"]);
+    }
+}
@@ -0,0 +1,62 @@
+use std::fmt;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+
+#[derive(Debug, Clone)]
+pub enum AoiError {
+    /// Unknown user or resource
+    NotFound(String),
+    /// Known user without any listen to show
+    NoListens(String),
+    /// An upstream service failed or answered with something unusable
+    Upstream(String),
+    /// An upstream service did not answer in time
+    Timeout(String),
+    /// The widget could not be templated, rendered or encoded
+    Render(String),
+}
+
+impl AoiError {
+    /// Wraps a failed HTTP call to an upstream service, telling timeouts apart.
+    pub fn from_reqwest(context: &str, err: reqwest::Error) -> AoiError {
+        if err.is_timeout() {
+            AoiError::Timeout(format!("{}: {}", context, err))
+        } else if err.status() == Some(reqwest::StatusCode::NOT_FOUND) {
+            AoiError::NotFound(format!("{}: {}", context, err))
+        } else {
+            AoiError::Upstream(format!("{}: {}", context, err))
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            AoiError::NotFound(_) | AoiError::NoListens(_) => StatusCode::NOT_FOUND,
+            AoiError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            AoiError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            AoiError::Render(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for AoiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AoiError::NotFound(message)
+            | AoiError::NoListens(message)
+            | AoiError::Upstream(message)
+            | AoiError::Timeout(message)
+            | AoiError::Render(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for AoiError {}
+
+impl IntoResponse for AoiError {
+    fn into_response(self) -> Response {
+        (self.status_code(), self.to_string()).into_response()
+    }
+}
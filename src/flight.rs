@@ -0,0 +1,77 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+
+use crate::error::AoiError;
+
+type Flight<T> = Shared<BoxFuture<'static, Result<T, AoiError>>>;
+
+/// Coalesces concurrent work on the same key, so only one future runs per key
+/// at a time and every caller waiting on it shares the result.
+#[derive(Clone)]
+pub struct SingleFlight<T> {
+    inflight: Arc<Mutex<HashMap<String, Flight<T>>>>,
+}
+
+impl<T> Default for SingleFlight<T> {
+    fn default() -> Self {
+        Self {
+            inflight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> SingleFlight<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `work` unless a flight for `key` is already running, in which case
+    /// its result is awaited instead. The work is spawned, so it completes even
+    /// if every caller goes away.
+    pub async fn run<F>(&self, key: &str, work: F) -> Result<T, AoiError>
+    where
+        F: Future<Output = Result<T, AoiError>> + Send + 'static,
+    {
+        let flight = {
+            let mut inflight = self.inflight.lock().unwrap();
+
+            match inflight.get(key) {
+                Some(flight) => flight.clone(),
+                None => {
+                    let cleanup = self.inflight.clone();
+                    let cleanup_key = key.to_string();
+
+                    // the task cannot remove its entry before it is inserted,
+                    // as the lock is held until then
+                    let handle = tokio::spawn(async move {
+                        let result = work.await;
+                        cleanup.lock().unwrap().remove(&cleanup_key);
+                        result
+                    });
+
+                    let flight = async move {
+                        match handle.await {
+                            Ok(val) => val,
+                            Err(err) => Err(AoiError::Render(format!("Task failed: {}", err))),
+                        }
+                    }
+                    .boxed()
+                    .shared();
+
+                    inflight.insert(key.to_string(), flight.clone());
+                    flight
+                }
+            }
+        };
+
+        flight.await
+    }
+}
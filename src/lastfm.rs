@@ -0,0 +1,403 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::{
+    error::AoiError,
+    listenbrainz::DEFAULT_TIMEOUT,
+    playing::{ListenData, ListenMetadata},
+    source::ListenSource,
+};
+
+const API_ROOT_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+
+#[derive(Deserialize)]
+struct RecentTracksResponse {
+    recenttracks: RecentTracks,
+}
+
+/// Body of failed calls, sent with either an error or a success status.
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: i32,
+    #[serde(default)]
+    message: String,
+}
+
+/// Error code of an unknown user.
+const USER_NOT_FOUND: i32 = 6;
+
+#[derive(Deserialize)]
+struct RecentTracks {
+    #[serde(default)]
+    track: OneOrMany<Track>,
+}
+
+/// Last.fm returns a single object instead of an array when there is only one item.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> Default for OneOrMany<T> {
+    fn default() -> Self {
+        OneOrMany::Many(Vec::new())
+    }
+}
+
+impl<T> OneOrMany<T> {
+    fn into_vec(self) -> Vec<T> {
+        match self {
+            OneOrMany::One(val) => vec![val],
+            OneOrMany::Many(val) => val,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Text {
+    #[serde(rename = "#text", default)]
+    text: String,
+    #[serde(default)]
+    mbid: String,
+}
+
+#[derive(Deserialize)]
+struct Image {
+    #[serde(rename = "#text", default)]
+    text: String,
+    size: String,
+}
+
+#[derive(Deserialize)]
+struct TrackAttr {
+    #[serde(default)]
+    nowplaying: String,
+}
+
+#[derive(Deserialize)]
+struct TrackDate {
+    uts: String,
+}
+
+#[derive(Deserialize)]
+struct Track {
+    name: String,
+    artist: Text,
+    album: Option<Text>,
+    #[serde(default)]
+    image: Vec<Image>,
+    date: Option<TrackDate>,
+    #[serde(rename = "@attr")]
+    attr: Option<TrackAttr>,
+}
+
+impl Track {
+    fn listening(&self) -> bool {
+        self.attr
+            .as_ref()
+            .is_some_and(|attr| attr.nowplaying == "true")
+    }
+}
+
+/// Reads listens from the Last.fm `user.getRecentTracks` API.
+pub struct LastFmSource {
+    api_root_url: String,
+    api_key: String,
+    timeout: Duration,
+    http: reqwest::Client,
+}
+
+impl LastFmSource {
+    pub fn new(api_key: impl ToString) -> Self {
+        Self::new_with_url(API_ROOT_URL, api_key, DEFAULT_TIMEOUT)
+    }
+
+    /// Construct a source for a Last.fm-compatible API, where every call fails
+    /// after `timeout`.
+    pub fn new_with_url(url: impl ToString, api_key: impl ToString, timeout: Duration) -> Self {
+        Self {
+            api_root_url: url.to_string(),
+            api_key: api_key.to_string(),
+            timeout,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    async fn recent_tracks(&self, user: &str, limit: u64) -> Result<Vec<Track>, AoiError> {
+        let response = match self
+            .http
+            .get(&self.api_root_url)
+            .query(&[
+                ("method", "user.getrecenttracks"),
+                ("user", user),
+                ("api_key", &self.api_key),
+                ("format", "json"),
+                ("limit", &limit.to_string()),
+            ])
+            .timeout(self.timeout)
+            .send()
+            .await
+        {
+            Ok(val) => val,
+            Err(err) => {
+                return Err(AoiError::from_reqwest(
+                    "Error while getting Last.fm recent tracks",
+                    err,
+                ))
+            }
+        };
+
+        let status = response.status();
+        let body = match response.text().await {
+            Ok(val) => val,
+            Err(err) => {
+                return Err(AoiError::from_reqwest(
+                    "Error while getting Last.fm recent tracks",
+                    err,
+                ))
+            }
+        };
+
+        // errors come with a code in the body, whatever the status
+        if let Ok(err) = serde_json::from_str::<ErrorResponse>(&body) {
+            let message = format!("Last.fm error {}: {}", err.error, err.message);
+
+            return Err(match err.error {
+                USER_NOT_FOUND => AoiError::NotFound(message),
+                _ => AoiError::Upstream(message),
+            });
+        }
+
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Err(AoiError::NotFound(format!(
+                "Last.fm user [{}] not found",
+                user
+            )));
+        }
+
+        if !status.is_success() {
+            return Err(AoiError::Upstream(format!(
+                "Error while getting Last.fm recent tracks: {}",
+                status
+            )));
+        }
+
+        match serde_json::from_str::<RecentTracksResponse>(&body) {
+            Ok(val) => Ok(val.recenttracks.track.into_vec()),
+            Err(err) => Err(AoiError::Upstream(format!(
+                "Error while parsing Last.fm recent tracks: {}",
+                err
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl ListenSource for LastFmSource {
+    fn key(&self) -> String {
+        "lastfm".to_string()
+    }
+
+    fn name(&self) -> String {
+        "Last.fm".to_string()
+    }
+
+    async fn now_playing(&self, user: &str) -> Result<ListenData, AoiError> {
+        let tracks = self.recent_tracks(user, 1).await?;
+
+        match tracks.into_iter().find(|track| track.listening()) {
+            Some(track) => Ok(listen_data(track)),
+            None => Err(AoiError::NoListens(format!(
+                "User [{}] is not listening to anything",
+                user
+            ))),
+        }
+    }
+
+    async fn previous_listens(&self, user: &str, count: u64) -> Result<Vec<ListenData>, AoiError> {
+        let tracks = self.recent_tracks(user, count).await?;
+
+        // the playing track is returned on top of the requested limit
+        Ok(tracks
+            .into_iter()
+            .filter(|track| !track.listening())
+            .take(count as usize)
+            .map(listen_data)
+            .collect())
+    }
+}
+
+fn listen_data(track: Track) -> ListenData {
+    let release = track
+        .album
+        .as_ref()
+        .map(|album| album.mbid.clone())
+        .filter(|val| !val.is_empty());
+    let release_name = track
+        .album
+        .as_ref()
+        .map(|album| album.text.clone())
+        .filter(|val| !val.is_empty());
+
+    // sizes are listed from smallest to largest
+    let image_url = track
+        .image
+        .iter()
+        .rev()
+        .find(|image| !image.text.is_empty() && image.size != "mega")
+        .map(|image| image.text.clone());
+
+    ListenData {
+        listened_at: if track.listening() {
+            None
+        } else {
+            track.date.and_then(|date| date.uts.parse::<i64>().ok())
+        },
+        title: track.name,
+        artist: track.artist.text,
+        metadata: Some(ListenMetadata {
+            release,
+            release_name,
+            release_group: None,
+            spotify_path: None,
+            image_url,
+            cover_art_id: None,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::{extract::Query, http::StatusCode, routing::get, Json, Router};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::mock::serve;
+
+    fn track(name: &str, listening: bool) -> Value {
+        let mut track = json!({
+            "name": name,
+            "artist": { "#text": "Artist", "mbid": "" },
+            "album": { "#text": "Album", "mbid": "" },
+            "image": [
+                { "#text": "https://example.com/small.jpg", "size": "small" },
+                { "#text": "https://example.com/large.jpg", "size": "extralarge" }
+            ],
+            "date": { "uts": "1700000000" }
+        });
+
+        if listening {
+            track["@attr"] = json!({ "nowplaying": "true" });
+            track.as_object_mut().unwrap().remove("date");
+        }
+
+        track
+    }
+
+    /// Answers after the `user` parameter, as Last.fm would for such users.
+    async fn source() -> LastFmSource {
+        let router = Router::new().route(
+            "/",
+            get(|Query(query): Query<HashMap<String, String>>| async move {
+                match query["user"].as_str() {
+                    "single" => (
+                        StatusCode::OK,
+                        Json(json!({ "recenttracks": { "track": track("Only", false) } })),
+                    ),
+                    "many" => (
+                        StatusCode::OK,
+                        Json(json!({ "recenttracks": { "track": [
+                            track("Playing", true),
+                            track("First", false),
+                            track("Second", false)
+                        ] } })),
+                    ),
+                    "error" => (
+                        StatusCode::OK,
+                        Json(json!({ "error": 6, "message": "User not found" })),
+                    ),
+                    "invalid" => (
+                        StatusCode::FORBIDDEN,
+                        Json(json!({ "error": 10, "message": "Invalid API key" })),
+                    ),
+                    _ => (
+                        StatusCode::NOT_FOUND,
+                        Json(json!({ "error": 6, "message": "User not found" })),
+                    ),
+                }
+            }),
+        );
+
+        LastFmSource::new_with_url(serve(router).await, "key", Duration::from_secs(5))
+    }
+
+    #[tokio::test]
+    async fn single_track_object() {
+        let listens = source().await.previous_listens("single", 5).await.unwrap();
+
+        assert_eq!(listens.len(), 1);
+        assert_eq!(listens[0].title, "Only");
+        assert_eq!(listens[0].listened_at, Some(1700000000));
+    }
+
+    #[tokio::test]
+    async fn track_array() {
+        let listens = source().await.previous_listens("many", 5).await.unwrap();
+
+        let titles: Vec<&str> = listens.iter().map(|val| val.title.as_str()).collect();
+        assert_eq!(titles, ["First", "Second"]);
+    }
+
+    #[tokio::test]
+    async fn previous_listens_skip_now_playing() {
+        let listens = source().await.previous_listens("many", 1).await.unwrap();
+
+        assert_eq!(listens.len(), 1);
+        assert_eq!(listens[0].title, "First");
+    }
+
+    #[tokio::test]
+    async fn now_playing_is_listening() {
+        let source = source().await;
+
+        let listen = source.now_playing("many").await.unwrap();
+        assert_eq!(listen.title, "Playing");
+        assert_eq!(listen.listened_at, None);
+
+        let metadata = listen.metadata.unwrap();
+        assert_eq!(metadata.release_name.as_deref(), Some("Album"));
+        assert_eq!(
+            metadata.image_url.as_deref(),
+            Some("https://example.com/large.jpg")
+        );
+
+        assert!(matches!(
+            source.now_playing("single").await,
+            Err(AoiError::NoListens(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_user_is_not_found() {
+        let source = source().await;
+
+        assert!(matches!(
+            source.previous_listens("missing", 1).await,
+            Err(AoiError::NotFound(_))
+        ));
+        assert!(matches!(
+            source.previous_listens("error", 1).await,
+            Err(AoiError::NotFound(_))
+        ));
+        assert!(matches!(
+            source.previous_listens("invalid", 1).await,
+            Err(AoiError::Upstream(_))
+        ));
+    }
+}
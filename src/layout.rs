@@ -0,0 +1,224 @@
+/// Arrangement of the now playing and previous listen widgets, each with its
+/// own template.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Cover beside the title and artist
+    Horizontal,
+    /// Single line badge
+    Compact,
+    /// Square card with the text over a blurred cover
+    Card,
+    /// Cover above the text, for sidebars
+    Vertical,
+}
+
+/// Font and room given to a line of text.
+pub struct TextSlot<'a> {
+    pub font_family: &'a str,
+    pub font_weight: &'static str,
+    /// Sizes to try, largest first
+    pub font_sizes: Vec<i32>,
+    pub width: i32,
+}
+
+pub struct LayoutSlots<'a> {
+    pub title: TextSlot<'a>,
+    pub artist: TextSlot<'a>,
+    /// The artist follows a single line title, sharing the artist slot width
+    pub inline: bool,
+}
+
+const BASE_PADDING: i32 = 24;
+/// Font size of the "Listening to" label
+const LABEL_SIZE: i32 = 20;
+
+impl Layout {
+    pub fn from_param(layout: Option<&String>) -> Layout {
+        match layout.map(|val| val.to_lowercase()).as_deref() {
+            Some("compact") => Layout::Compact,
+            Some("card") => Layout::Card,
+            Some("vertical") => Layout::Vertical,
+            _ => Layout::Horizontal,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layout::Horizontal => "horizontal",
+            Layout::Compact => "compact",
+            Layout::Card => "card",
+            Layout::Vertical => "vertical",
+        }
+    }
+
+    pub fn template(&self) -> &'static str {
+        match self {
+            Layout::Horizontal => "widget.html",
+            Layout::Compact => "compact.html",
+            Layout::Card => "card.html",
+            Layout::Vertical => "vertical.html",
+        }
+    }
+
+    /// Only the horizontal layout scrolls text that is too long.
+    pub fn scrolls(&self) -> bool {
+        *self == Layout::Horizontal
+    }
+
+    /// Widget size from the requested width and height, within the bounds of
+    /// the layout. Cards are square, and vertical cards grow to fit
+    /// their text, so both only take a width.
+    pub fn size(&self, width: Option<i32>, height: Option<i32>) -> (i32, i32) {
+        match self {
+            Layout::Horizontal => {
+                let height = height.unwrap_or(200).clamp(160, 400);
+                // the text needs at least as much room as the cover
+                let width = width.unwrap_or(1000).clamp(400.max(height * 2), 2000);
+
+                (width, height)
+            }
+            Layout::Compact => {
+                let height = height.unwrap_or(64).clamp(40, 96);
+                let width = width.unwrap_or(600).clamp(300.max(height * 4), 1200);
+
+                (width, height)
+            }
+            Layout::Card => {
+                let width = width.unwrap_or(400).clamp(320, 640);
+
+                (width, width)
+            }
+            Layout::Vertical => {
+                let width = width.unwrap_or(300).clamp(240, 480);
+
+                (width, width)
+            }
+        }
+    }
+
+    pub fn slots<'a>(&self, width: i32, height: i32, font_family: &'a str) -> LayoutSlots<'a> {
+        let slot = |font_weight, font_sizes, width| TextSlot {
+            font_family,
+            font_weight,
+            font_sizes,
+            width,
+        };
+
+        match self {
+            Layout::Horizontal => {
+                let text_width = width - height - BASE_PADDING;
+
+                LayoutSlots {
+                    title: slot("700", vec![36, 32, 28, 24], text_width),
+                    artist: slot("400", vec![28, 24, 20], text_width),
+                    inline: false,
+                }
+            }
+            Layout::Compact => {
+                let font_size = height * 3 / 8;
+                let text_width = width - height - 16 - compact_icon_room(font_size);
+
+                LayoutSlots {
+                    title: slot("700", vec![font_size], text_width * 2 / 3),
+                    artist: slot("400", vec![font_size - 2], text_width),
+                    inline: true,
+                }
+            }
+            Layout::Card | Layout::Vertical => {
+                let text_width = width - BASE_PADDING - BASE_PADDING;
+
+                LayoutSlots {
+                    title: slot("700", vec![28, 24, 20], text_width),
+                    artist: slot("400", vec![22, 20, 18], text_width),
+                    inline: false,
+                }
+            }
+        }
+    }
+
+    pub fn cover_size(&self, width: i32, height: i32) -> i32 {
+        match self {
+            Layout::Horizontal => height - BASE_PADDING - BASE_PADDING,
+            Layout::Compact => height - 16,
+            Layout::Card => width * 9 / 20,
+            Layout::Vertical => width - BASE_PADDING - BASE_PADDING,
+        }
+    }
+
+    /// Whether the text fits in the widget, following the line positions of
+    /// the layout template.
+    pub fn text_fits(
+        &self,
+        width: i32,
+        height: i32,
+        title_lines: usize,
+        title_size: i32,
+        artist_size: i32,
+    ) -> bool {
+        let text_top = match self {
+            Layout::Horizontal => BASE_PADDING + 16,
+            Layout::Compact => return title_lines == 1,
+            Layout::Card => BASE_PADDING + self.cover_size(width, height) + 16,
+            Layout::Vertical => return true,
+        };
+
+        let title_y = text_top
+            + LABEL_SIZE
+            + 12
+            + title_size
+            + (title_lines as i32 - 1) * line_height(title_size);
+        let artist_bottom = title_y + 16 + artist_size + artist_size / 4;
+
+        artist_bottom <= height - BASE_PADDING
+    }
+}
+
+/// Room taken by the listening icon of the compact layout and its gap, the
+/// template places it the same way.
+fn compact_icon_room(font_size: i32) -> i32 {
+    font_size * 3 / 2
+}
+
+pub fn line_height(font_size: i32) -> i32 {
+    font_size * 6 / 5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_defaults() {
+        assert_eq!(Layout::Horizontal.size(None, None), (1000, 200));
+        assert_eq!(Layout::Compact.size(None, None), (600, 64));
+        assert_eq!(Layout::Card.size(None, None), (400, 400));
+        assert_eq!(Layout::Vertical.size(None, None), (300, 300));
+    }
+
+    #[test]
+    fn size_is_clamped() {
+        assert_eq!(Layout::Horizontal.size(Some(100), Some(1000)), (800, 400));
+        assert_eq!(Layout::Horizontal.size(Some(5000), Some(10)), (2000, 160));
+        assert_eq!(Layout::Compact.size(Some(100), Some(10)), (300, 40));
+        // the text keeps at least three times the cover width
+        assert_eq!(Layout::Compact.size(Some(300), Some(96)), (384, 96));
+        assert_eq!(Layout::Card.size(Some(1000), Some(100)), (640, 640));
+        assert_eq!(Layout::Vertical.size(Some(100), None), (240, 240));
+    }
+
+    #[test]
+    fn compact_keeps_a_single_inline_line() {
+        let (width, height) = Layout::Compact.size(None, None);
+
+        assert!(Layout::Compact.slots(width, height, "Inter").inline);
+        assert!(Layout::Compact.text_fits(width, height, 1, 24, 22));
+        assert!(!Layout::Compact.text_fits(width, height, 2, 24, 22));
+    }
+
+    #[test]
+    fn horizontal_fits_lines_by_height() {
+        assert!(Layout::Horizontal.text_fits(1000, 200, 1, 36, 28));
+        assert!(!Layout::Horizontal.text_fits(1000, 200, 3, 36, 28));
+        assert!(Layout::Horizontal.text_fits(1000, 400, 3, 36, 28));
+    }
+}
@@ -1,4 +1,19 @@
+pub mod config;
+pub mod cover;
+pub mod error;
+pub mod flight;
+pub mod lastfm;
+pub mod layout;
+pub mod listenbrainz;
 pub mod logger;
+#[cfg(test)]
+mod mock;
+pub mod palette;
+pub mod placeholder;
 pub mod playing;
 pub mod render;
+pub mod source;
+pub mod subsonic;
 pub mod template;
+pub mod text;
+pub mod theme;
@@ -0,0 +1,145 @@
+use std::{collections::HashMap, time::Duration};
+
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::error::AoiError;
+
+pub const API_ROOT_URL: &str = "https://api.listenbrainz.org/1/";
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+pub struct PlayingNowResponse {
+    pub payload: PlayingNowPayload,
+}
+
+#[derive(Deserialize)]
+pub struct PlayingNowPayload {
+    #[serde(default)]
+    pub listens: Vec<PlayingNowListen>,
+}
+
+#[derive(Deserialize)]
+pub struct PlayingNowListen {
+    pub track_metadata: TrackMetadata,
+}
+
+#[derive(Deserialize)]
+pub struct ListensResponse {
+    pub payload: ListensPayload,
+}
+
+#[derive(Deserialize)]
+pub struct ListensPayload {
+    #[serde(default)]
+    pub listens: Vec<Listen>,
+}
+
+#[derive(Deserialize)]
+pub struct Listen {
+    pub listened_at: i64,
+    pub track_metadata: TrackMetadata,
+}
+
+#[derive(Deserialize)]
+pub struct TrackMetadata {
+    pub artist_name: String,
+    pub track_name: String,
+    pub release_name: Option<String>,
+    #[serde(default)]
+    pub additional_info: HashMap<String, serde_json::Value>,
+    pub mbid_mapping: Option<MbidMapping>,
+}
+
+#[derive(Deserialize)]
+pub struct MbidMapping {
+    pub recording_mbid: Option<String>,
+    pub release_mbid: Option<String>,
+}
+
+/// Async client for the ListenBrainz endpoints used by the widgets.
+pub struct Client {
+    api_root_url: String,
+    timeout: Duration,
+    http: reqwest::Client,
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Self::new_with_url(API_ROOT_URL, DEFAULT_TIMEOUT)
+    }
+
+    /// Construct a client for a ListenBrainz-compatible server, where every
+    /// call fails after `timeout`.
+    pub fn new_with_url(url: impl ToString, timeout: Duration) -> Self {
+        Self {
+            api_root_url: url.to_string(),
+            timeout,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn user_playing_now(&self, user: &str) -> Result<PlayingNowResponse, AoiError> {
+        self.get(
+            &["user", user, "playing-now"],
+            &[],
+            "Error while getting user now playing",
+        )
+        .await
+    }
+
+    pub async fn user_listens(&self, user: &str, count: u64) -> Result<ListensResponse, AoiError> {
+        self.get(
+            &["user", user, "listens"],
+            &[("count", count.to_string())],
+            "Error while getting user listens",
+        )
+        .await
+    }
+
+    async fn get<R: for<'de> Deserialize<'de>>(
+        &self,
+        path: &[&str],
+        query: &[(&str, String)],
+        context: &str,
+    ) -> Result<R, AoiError> {
+        // segments are percent-encoded, so user names cannot escape the endpoint
+        let mut url = match Url::parse(&self.api_root_url) {
+            Ok(val) => val,
+            Err(err) => {
+                return Err(AoiError::Upstream(format!(
+                    "Invalid API root URL [{}]: {}",
+                    self.api_root_url, err
+                )))
+            }
+        };
+
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().extend(path);
+        }
+
+        let response = match self
+            .http
+            .get(url)
+            .query(query)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .and_then(|val| val.error_for_status())
+        {
+            Ok(val) => val,
+            Err(err) => return Err(AoiError::from_reqwest(context, err)),
+        };
+
+        match response.json::<R>().await {
+            Ok(val) => Ok(val),
+            Err(err) => Err(AoiError::from_reqwest(context, err)),
+        }
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,30 +1,42 @@
 use std::{
     collections::HashMap,
-    time::{Duration, Instant},
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use aoi::{
+    config::Config,
+    cover::CoverArtChain,
+    error::AoiError,
+    flight::SingleFlight,
+    lastfm::LastFmSource,
+    layout::Layout,
     logger::SimpleLogger,
-    playing::{
-        cover_art_by_release_group, cover_art_by_spotify_path, now_playing, previous_listen,
-        ListenMetadata,
+    palette::{accent_theme, album_theme, art_theme, Palette, ALBUM_THEME},
+    placeholder::{placeholder_art, placeholder_key},
+    playing::{CoverArt, ListenData},
+    render::{font_options, render_template, ImageFormat},
+    source::{ListenBrainzSource, ListenSource},
+    subsonic::SubsonicSource,
+    template::{
+        error_template, playing_template, recent_template, PlayingWidget, RECENT_COVER_SIZE,
     },
-    template::playing_template,
+    text::TextMeasurer,
+    theme::{Theme, Themes},
 };
 use axum::{
     extract::{Path, Query, State},
-    http::header,
-    response::IntoResponse,
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
     routing::get,
-    Router,
+    Extension, Router,
 };
-use listenbrainz::raw::Client;
-use log::{error, info, LevelFilter};
+use futures::future::join_all;
+use log::{error, info, warn, LevelFilter};
 use moka::future::Cache;
-use reqwest::StatusCode;
-use resvg::{render, tiny_skia::Pixmap};
 use tera::Tera;
-use usvg::{Options, Transform, Tree};
+use usvg::Options;
 
 #[derive(Clone)]
 pub struct ResponseCache {
@@ -35,12 +47,24 @@ pub struct ResponseCache {
 #[derive(Clone)]
 pub struct AppState {
     pub tera: Tera,
+    /// Render options holding the font database, loaded once at startup
+    pub options: Arc<Options<'static>>,
+    pub measurer: Arc<TextMeasurer>,
+    pub themes: Arc<Themes>,
     pub response_cache: Cache<String, ResponseCache>,
-    pub cover_art_cache: Cache<String, String>,
+    /// Cover art scaled to a size, keyed `{provider}-{key}@{size}`
+    pub cover_art_cache: Cache<String, CoverArt>,
+    /// Fetched cover art before scaling, keyed `{provider}-{key}`
+    pub cover_art_data: Cache<String, Arc<[u8]>>,
+    /// Cover art lookups tried in order, see `[cover_art]` in the config
+    pub cover_art_chain: Arc<CoverArtChain>,
+    pub render_flight: SingleFlight<Vec<u8>>,
 }
 
 static LOGGER: SimpleLogger = SimpleLogger;
 
+const MAX_WRAP: usize = 3;
+
 #[tokio::main]
 async fn main() {
     let _ = log::set_logger(&LOGGER).map(|()| log::set_max_level(LevelFilter::Info));
@@ -54,14 +78,24 @@ async fn main() {
         .build();
     // cache cover art data for 1 day
     let cover_art_cache = Cache::builder()
-        .time_to_live(Duration::from_secs(1 * 24 * 60 * 60))
+        .time_to_live(Duration::from_secs(24 * 60 * 60))
         // A weigher closure takes &K and &V and returns a u32 representing the
         // relative size of the entry. Here, we use the byte length of the value
         // String as the size.
-        .weigher(|_key, value: &String| -> u32 { value.len().try_into().unwrap_or(u32::MAX) })
+        .weigher(|_key, value: &CoverArt| -> u32 {
+            value.encoded.len().try_into().unwrap_or(u32::MAX)
+        })
         // This cache will hold up to 64MiB of values.
         .max_capacity(64 * 1024 * 1024)
         .build();
+    // keep fetched cover art briefly, so sizes requested together are scaled
+    // from a single fetch
+    let cover_art_data = Cache::builder()
+        .time_to_live(Duration::from_secs(5 * 60))
+        .weigher(|_key, value: &Arc<[u8]>| -> u32 { value.len().try_into().unwrap_or(u32::MAX) })
+        // This cache will hold up to 8MiB of values.
+        .max_capacity(8 * 1024 * 1024)
+        .build();
 
     let tera = match Tera::new("templates/**/*.html") {
         Ok(t) => t,
@@ -71,15 +105,88 @@ async fn main() {
         }
     };
 
-    // build our application with a single route
-    let app = Router::new()
-        .route("/{id}", get(get_playing_now))
-        .route("/{id}/previous", get(get_playing_now))
-        .with_state(AppState {
-            tera,
-            response_cache,
-            cover_art_cache,
-        });
+    let config = match Config::load() {
+        Ok(val) => val,
+        Err(e) => {
+            error!("Config error: {}", e);
+            ::std::process::exit(1);
+        }
+    };
+
+    let themes = match Themes::load(&config.themes.directory) {
+        Ok(val) => val,
+        Err(e) => {
+            error!("Theme error: {}", e);
+            ::std::process::exit(1);
+        }
+    };
+
+    let cover_art_chain = match CoverArtChain::from_config(&config.cover_art) {
+        Ok(val) => val,
+        Err(e) => {
+            error!("Cover art error: {}", e);
+            ::std::process::exit(1);
+        }
+    };
+
+    let options = Arc::new(font_options(config.fonts.directory.as_deref()));
+
+    let listenbrainz: Arc<dyn ListenSource> = Arc::new(ListenBrainzSource::new_with_url(
+        "listenbrainz",
+        &config.listenbrainz.api_root_url,
+        Duration::from_secs(config.listenbrainz.timeout),
+    ));
+
+    // the prefixed routes stay reachable for users named after another prefix
+    let mut app =
+        widget_routes(listenbrainz.clone()).nest("/listenbrainz", widget_routes(listenbrainz));
+
+    match config.lastfm.api_key {
+        Some(api_key) => {
+            app = app.nest(
+                "/lastfm",
+                widget_routes(Arc::new(LastFmSource::new(api_key))),
+            )
+        }
+        None => info!("Last.fm API key is not set, Last.fm routes are disabled"),
+    }
+
+    if let Some(subsonic) = &config.subsonic {
+        info!("Serving Subsonic now playing from {}", subsonic.url);
+        app = app.nest(
+            "/subsonic",
+            widget_routes(Arc::new(SubsonicSource::new(
+                &subsonic.url,
+                &subsonic.username,
+                &subsonic.password,
+            ))),
+        );
+    }
+
+    // only configured instances are routed, so arbitrary servers cannot be proxied
+    for (name, instance) in &config.instances {
+        info!("Serving instance [{}] from {}", name, instance.api_root_url);
+        app = app.nest(
+            &format!("/i/{}", name),
+            widget_routes(Arc::new(ListenBrainzSource::new_with_url(
+                format!("instance-{}", name),
+                &instance.api_root_url,
+                Duration::from_secs(instance.timeout),
+            ))),
+        );
+    }
+
+    let app = app.with_state(AppState {
+        tera,
+        measurer: Arc::new(TextMeasurer::new(options.clone())),
+        themes: Arc::new(themes),
+        options,
+        response_cache,
+        cover_art_cache,
+        cover_art_data,
+        cover_art_chain: Arc::new(cover_art_chain),
+        render_flight: SingleFlight::new(),
+    });
 
     // run our app with hyper, listening globally on port 3000
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
@@ -88,221 +195,642 @@ async fn main() {
     info!("App started!");
 }
 
+fn widget_routes(source: Arc<dyn ListenSource>) -> Router<AppState> {
+    let router = Router::new().route("/{id}", get(get_playing_now));
+
+    let router = match source.has_history() {
+        true => router
+            .route("/{id}/previous", get(get_previous))
+            .route("/{id}/recent", get(get_recent)),
+        false => router,
+    };
+
+    router.layer(Extension(source))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Widget {
+    /// Currently playing track, falling back to the last listen
+    NowPlaying,
+    /// Last completed listen, even while something is playing
+    Previous,
+    /// List of the given number of last listens
+    Recent(u64),
+}
+
+impl Widget {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Widget::NowPlaying => "now-playing",
+            Widget::Previous => "previous",
+            Widget::Recent(_) => "recent",
+        }
+    }
+
+    pub fn key(&self) -> String {
+        match self {
+            Widget::Recent(count) => format!("{}{}", self.name(), count),
+            _ => self.name().to_string(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RenderOptions {
+    pub theme: Option<String>,
+    /// Alias of `theme` for the built-in ones, and the base of the album theme
+    pub color_mode: Option<String>,
+    pub fill: Option<String>,
+    pub transparent: Option<String>,
+    pub mirror: Option<String>,
+    /// Lines the title may wrap across
+    pub wrap: usize,
+    /// `scroll` animates text too wide for the widget in SVG output
+    pub overflow: Option<String>,
+    /// `art` draws the blurred cover behind the widget
+    pub background: Option<String>,
+    pub layout: Layout,
+    pub width: i32,
+    pub height: i32,
+    /// Pixel density of raster output, 1 for SVG
+    pub scale: u8,
+    pub format: ImageFormat,
+    pub quality: Option<u8>,
+}
+
+impl RenderOptions {
+    pub fn from_request(params: &HashMap<String, String>, headers: &HeaderMap) -> RenderOptions {
+        let format = ImageFormat::negotiate(
+            params.get("format"),
+            headers
+                .get(header::ACCEPT)
+                .and_then(|val| val.to_str().ok()),
+        );
+
+        let layout = Layout::from_param(params.get("layout"));
+        let (width, height) = layout.size(
+            params.get("width").and_then(|val| val.parse::<i32>().ok()),
+            params.get("height").and_then(|val| val.parse::<i32>().ok()),
+        );
+
+        RenderOptions {
+            theme: params.get("theme").cloned(),
+            color_mode: params.get("color_mode").cloned(),
+            fill: params.get("fill").cloned(),
+            transparent: params.get("transparent").cloned(),
+            mirror: params.get("mirror").cloned(),
+            wrap: params
+                .get("wrap")
+                .and_then(|val| val.parse::<usize>().ok())
+                .unwrap_or(1)
+                .clamp(1, MAX_WRAP),
+            overflow: params.get("overflow").cloned(),
+            background: params.get("background").cloned(),
+            layout,
+            width,
+            height,
+            scale: format.scale(params.get("scale")),
+            format,
+            quality: format.quality(params.get("quality")),
+        }
+    }
+
+    pub fn cache_key(&self, source: &dyn ListenSource, widget: Widget, id: &str) -> String {
+        format!(
+            "{}-{}-{}-{}-{}-{}-{}-{}-{}-{}-{}-{}-{}x{}@{}-{}-{}",
+            source.key(),
+            widget.key(),
+            id,
+            self.theme.clone().unwrap_or("default".to_string()),
+            self.color_mode.clone().unwrap_or("default".to_string()),
+            self.fill.clone().unwrap_or("default".to_string()),
+            self.transparent.clone().unwrap_or("default".to_string()),
+            self.mirror.is_some(),
+            self.wrap,
+            self.scroll(),
+            self.background.clone().unwrap_or("default".to_string()),
+            self.layout.name(),
+            self.width,
+            self.height,
+            self.scale,
+            self.format.name(),
+            self.quality
+                .map(|val| val.to_string())
+                .unwrap_or("default".to_string())
+        )
+    }
+
+    /// Theme of the widget, coloured after the cover art for `theme=album` and
+    /// `fill=auto`. Without a palette, these keep the `color_mode` theme.
+    pub fn theme(&self, themes: &Themes, palette: Option<&Palette>) -> Theme {
+        let base = themes.get(self.color_mode.as_ref());
+
+        if self.theme.as_deref() == Some(ALBUM_THEME) {
+            return match palette {
+                Some(palette) => album_theme(palette, base),
+                None => base.clone(),
+            };
+        }
+
+        let theme = themes.get(self.theme.as_ref().or(self.color_mode.as_ref()));
+
+        match palette {
+            Some(palette) if self.fill.as_deref() == Some("auto") => accent_theme(palette, theme),
+            _ => theme.clone(),
+        }
+    }
+
+    /// Only SVG can animate, other formats ellipsize instead.
+    pub fn scroll(&self) -> bool {
+        self.overflow.as_deref() == Some("scroll") && self.format == ImageFormat::Svg
+    }
+}
+
 async fn get_playing_now(
     State(state): State<AppState>,
+    Extension(source): Extension<Arc<dyn ListenSource>>,
     Path(id): Path<String>,
     Query(params): Query<HashMap<String, String>>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let color_mode: Option<String> = params.get("color_mode").cloned();
-    let fill = params.get("fill").cloned();
-    let transparent = params.get("transparent").cloned();
-
-    if let Some(val) = state
-        .response_cache
-        .get(&format!(
-            "{}-{}-{}-{}",
-            id,
-            color_mode.clone().unwrap_or("default".to_string()),
-            fill.clone().unwrap_or("default".to_string()),
-            transparent.clone().unwrap_or("default".to_string())
-        ))
-        .await
-    {
-        info!("Cache HIT, user {}", id);
+    request_headers: HeaderMap,
+) -> Response {
+    let options = RenderOptions::from_request(&params, &request_headers);
+
+    get_widget(state, source, id, Widget::NowPlaying, options).await
+}
+
+async fn get_previous(
+    State(state): State<AppState>,
+    Extension(source): Extension<Arc<dyn ListenSource>>,
+    Path(id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    request_headers: HeaderMap,
+) -> Response {
+    let options = RenderOptions::from_request(&params, &request_headers);
+
+    get_widget(state, source, id, Widget::Previous, options).await
+}
+
+async fn get_recent(
+    State(state): State<AppState>,
+    Extension(source): Extension<Arc<dyn ListenSource>>,
+    Path(id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    request_headers: HeaderMap,
+) -> Response {
+    let options = RenderOptions::from_request(&params, &request_headers);
+    let count = params
+        .get("count")
+        .and_then(|val| val.parse::<u64>().ok())
+        .unwrap_or(5)
+        .clamp(1, 10);
+
+    get_widget(state, source, id, Widget::Recent(count), options).await
+}
 
-        if Instant::now().duration_since(val.timestamp).as_secs() > (60 * 1) {
+async fn get_widget(
+    state: AppState,
+    source: Arc<dyn ListenSource>,
+    id: String,
+    widget: Widget,
+    options: RenderOptions,
+) -> Response {
+    let cache_key = options.cache_key(source.as_ref(), widget, &id);
+
+    let headers = [
+        (
+            header::CONTENT_TYPE,
+            options.format.content_type().to_string(),
+        ),
+        (
+            header::CONTENT_DISPOSITION,
+            format!(
+                "inline; filename=\"{}.{}\"",
+                widget.name(),
+                options.format.name()
+            ),
+        ),
+        (header::VARY, header::ACCEPT.to_string()),
+    ];
+
+    if let Some(val) = state.response_cache.get(&cache_key).await {
+        info!("Cache HIT, {} of user {}", widget.name(), id);
+
+        if Instant::now().duration_since(val.timestamp).as_secs() > 60 {
             tokio::spawn(async move {
-                info!("Revalidating listen of user {}", id);
-                let result = render_listen(
-                    &state,
-                    &id,
-                    color_mode.as_ref(),
-                    fill.as_ref(),
-                    transparent.as_ref(),
-                )
-                .await
-                .unwrap();
-
-                state
-                    .response_cache
-                    .insert(
-                        format!(
-                            "{}-{}-{}-{}",
-                            id,
-                            color_mode.unwrap_or("default".to_string()),
-                            fill.unwrap_or("default".to_string()),
-                            transparent.unwrap_or("default".to_string())
-                        ),
-                        ResponseCache {
-                            timestamp: Instant::now(),
-                            response: result.clone(),
-                        },
-                    )
-                    .await;
+                info!("Revalidating {} of user {}", widget.name(), id);
+                if let Err(err) = refresh_widget(state, source, id.clone(), widget, options).await {
+                    warn!(
+                        "Cannot revalidate {} of user {}: {}",
+                        widget.name(),
+                        id,
+                        err
+                    );
+                }
             });
         }
 
-        return Ok((
-            [
-                (header::CONTENT_TYPE, "image/png"),
-                (
-                    header::CONTENT_DISPOSITION,
-                    "inline; filename=\"now-playing.png\"",
-                ),
-            ],
-            val.response,
-        ));
+        return (headers, val.response).into_response();
     };
 
-    let result = render_listen(
-        &state,
-        &id,
-        color_mode.as_ref(),
-        fill.as_ref(),
-        transparent.as_ref(),
+    let result = match refresh_widget(
+        state.clone(),
+        source.clone(),
+        id.clone(),
+        widget,
+        options.clone(),
     )
     .await
-    .unwrap();
+    {
+        Ok(val) => val,
+        Err(err) => {
+            error!("Cannot render {} of user {}: {}", widget.name(), id, err);
+
+            return match render_error(&state, source.as_ref(), &id, &options, &err).await {
+                Ok(val) => (
+                    err.status_code(),
+                    headers,
+                    [(header::CACHE_CONTROL, "no-cache")],
+                    val,
+                )
+                    .into_response(),
+                Err(render_err) => {
+                    error!("Cannot render error of user {}: {}", id, render_err);
+                    err.into_response()
+                }
+            };
+        }
+    };
+
+    (headers, result).into_response()
+}
+
+/// Renders a widget and stores it in the response cache. Concurrent refreshes
+/// of the same cache key share a single render.
+async fn refresh_widget(
+    state: AppState,
+    source: Arc<dyn ListenSource>,
+    id: String,
+    widget: Widget,
+    options: RenderOptions,
+) -> Result<Vec<u8>, AoiError> {
+    let cache_key = options.cache_key(source.as_ref(), widget, &id);
 
     state
-        .response_cache
-        .insert(
-            format!(
-                "{}-{}-{}-{}",
-                id,
-                color_mode.unwrap_or("default".to_string()),
-                fill.unwrap_or("default".to_string()),
-                transparent.unwrap_or("default".to_string())
-            ),
-            ResponseCache {
-                timestamp: Instant::now(),
-                response: result.clone(),
-            },
-        )
-        .await;
-
-    Ok((
-        [
-            (header::CONTENT_TYPE, "image/png"),
-            (
-                header::CONTENT_DISPOSITION,
-                "inline; filename=\"now-playing.png\"",
-            ),
-        ],
-        result,
-    ))
+        .render_flight
+        .clone()
+        .run(&cache_key.clone(), async move {
+            let result = render_widget(&state, source.as_ref(), &id, widget, &options).await?;
+
+            state
+                .response_cache
+                .insert(
+                    cache_key,
+                    ResponseCache {
+                        timestamp: Instant::now(),
+                        response: result.clone(),
+                    },
+                )
+                .await;
+
+            Ok(result)
+        })
+        .await
 }
 
-pub async fn render_listen(
+/// Renders a widget-shaped image describing why the listen could not be shown.
+pub async fn render_error(
     state: &AppState,
-    id: &String,
-    color_mode: Option<&String>,
-    fill: Option<&String>,
-    transparent: Option<&String>,
-) -> Result<Vec<u8>, String> {
-    const WIDTH: i32 = 1000;
-    const HEIGHT: i32 = 200;
-
-    info!("Getting listen of user {}", id);
-
-    let client = Client::new();
-
-    let (listen, listening) = match now_playing(&client, &id).await {
-        Ok(val) => (val, true),
-        Err(_) => match previous_listen(&client, &id).await {
-            Ok(val) => (val, false),
-            Err(err) => return Err(format!("User has no listen history, error: {:#?}", err)),
-        },
+    source: &dyn ListenSource,
+    id: &str,
+    options: &RenderOptions,
+    err: &AoiError,
+) -> Result<Vec<u8>, AoiError> {
+    let (title, message, empty) = match err {
+        AoiError::NoListens(_) => (
+            "Nothing played yet".to_string(),
+            format!("{} has no listens on {}", id, source.name()),
+            true,
+        ),
+        AoiError::NotFound(_) => (
+            "User not found".to_string(),
+            format!("{} is not a {} user", id, source.name()),
+            false,
+        ),
+        AoiError::Upstream(_) | AoiError::Timeout(_) => (
+            format!("{} unavailable", source.name()),
+            "Please try again later".to_string(),
+            false,
+        ),
+        AoiError::Render(_) => (
+            "Cannot render widget".to_string(),
+            "Please try again later".to_string(),
+            false,
+        ),
     };
 
-    let image = if let Some(metadata) = listen.metadata {
-        if let Ok(result) = get_cover_art(&metadata, &state.cover_art_cache).await {
-            result
-        } else {
-            None
-        }
-    } else {
-        None
+    // errors keep the horizontal layout, whatever the layout of the widget
+    let (width, height) = match options.layout {
+        Layout::Horizontal => (options.width, options.height),
+        _ => Layout::Horizontal.size(Some(options.width), None),
     };
 
-    let template = playing_template(
+    let template = error_template(
         &state.tera,
-        WIDTH,
-        HEIGHT,
-        &listen.title,
-        &listen.artist,
-        &image.unwrap_or_default(),
-        color_mode,
-        fill,
-        transparent.is_some(),
-        listening,
+        &state.measurer,
+        width,
+        height,
+        &title,
+        &message,
+        &options.theme(&state.themes, None),
+        options.fill.as_ref(),
+        options.transparent.is_some() && options.format.supports_alpha(),
+        empty,
+    )?;
+
+    render_template(
+        &state.options,
+        template,
+        options.format,
+        options.quality,
+        options.scale,
     )
-    .unwrap();
+    .await
+}
 
-    let mut opt = Options::default();
+pub async fn render_widget(
+    state: &AppState,
+    source: &dyn ListenSource,
+    id: &str,
+    widget: Widget,
+    options: &RenderOptions,
+) -> Result<Vec<u8>, AoiError> {
+    match widget {
+        Widget::Recent(count) => render_recent(state, source, id, count, options).await,
+        _ => render_listen(state, source, id, widget, options).await,
+    }
+}
 
-    opt.fontdb_mut().load_system_fonts();
+pub async fn render_recent(
+    state: &AppState,
+    source: &dyn ListenSource,
+    id: &str,
+    count: u64,
+    options: &RenderOptions,
+) -> Result<Vec<u8>, AoiError> {
+    info!(
+        "Getting {} recent listens of {} user {}",
+        count,
+        source.key(),
+        id
+    );
+
+    let listens = source.previous_listens(id, count).await?;
+
+    if listens.is_empty() {
+        return Err(AoiError::NoListens(format!(
+            "User [{}] does not have any listen",
+            id
+        )));
+    }
+
+    // look up each cover only once, even if several listens share a release
+    let mut unique: Vec<(String, &ListenData)> = Vec::new();
+    for listen in &listens {
+        if let Some(key) = listen.cover_art_key() {
+            if !unique.iter().any(|(val, _)| *val == key) {
+                unique.push((key, listen));
+            }
+        }
+    }
 
-    let tree = Tree::from_data(&template.as_bytes(), &opt).unwrap();
+    let size = RECENT_COVER_SIZE as u32 * options.scale as u32;
+    let images: HashMap<String, Option<CoverArt>> = join_all(
+        unique
+            .iter()
+            .map(|(_, listen)| get_cover_art(state, source, listen, size)),
+    )
+    .await
+    .into_iter()
+    .zip(unique)
+    .map(|(image, (key, _))| (key, image.unwrap_or_default()))
+    .collect();
+
+    let mut rows: Vec<(ListenData, Option<String>)> = Vec::with_capacity(listens.len());
+    for listen in &listens {
+        let image = match listen
+            .cover_art_key()
+            .and_then(|key| images.get(&key).cloned().flatten())
+        {
+            Some(val) => Some(val),
+            None => placeholder(state, listen, size, options).await,
+        };
+
+        rows.push((listen.clone(), image.map(|cover_art| cover_art.data_uri())));
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|val| val.as_secs() as i64)
+        .unwrap_or_default();
+
+    // album colours follow the latest cover
+    let palette = listens
+        .iter()
+        .filter_map(|listen| listen.cover_art_key())
+        .find_map(|key| images.get(&key).cloned().flatten())
+        .and_then(|cover_art| cover_art.palette);
+
+    let template = recent_template(
+        &state.tera,
+        &state.measurer,
+        // layouts only apply to single listens
+        Layout::Horizontal.size(Some(options.width), None).0,
+        &rows,
+        &options.theme(&state.themes, palette.as_ref()),
+        options.fill.as_ref(),
+        options.transparent.is_some() && options.format.supports_alpha(),
+        now,
+    )?;
+
+    render_template(
+        &state.options,
+        template,
+        options.format,
+        options.quality,
+        options.scale,
+    )
+    .await
+}
 
-    let tree_size = tree.size().to_int_size();
-    let mut pixmap = Pixmap::new(tree_size.width(), tree_size.height()).unwrap();
-    render(&tree, Transform::default(), &mut pixmap.as_mut());
+pub async fn render_listen(
+    state: &AppState,
+    source: &dyn ListenSource,
+    id: &str,
+    widget: Widget,
+    options: &RenderOptions,
+) -> Result<Vec<u8>, AoiError> {
+    info!("Getting {} of {} user {}", widget.name(), source.key(), id);
+
+    let (listen, listening) = match widget {
+        Widget::NowPlaying => match source.now_playing(id).await {
+            Ok(val) => (val, true),
+            Err(_) => (source.previous_listen(id).await?, false),
+        },
+        _ => (source.previous_listen(id).await?, false),
+    };
 
-    let result = pixmap.encode_png().unwrap();
+    // covers are scaled down to the pixels they cover in the output
+    let size =
+        options.layout.cover_size(options.width, options.height) as u32 * options.scale as u32;
+    let cover_art = match get_cover_art(state, source, &listen, size)
+        .await
+        .unwrap_or_default()
+    {
+        Some(val) => Some(val),
+        None => placeholder(state, &listen, size, options).await,
+    };
+    let (image, palette) = match cover_art {
+        Some(cover_art) => (cover_art.data_uri(), cover_art.palette),
+        None => (String::new(), None),
+    };
 
-    Ok(result)
+    let theme = options.theme(&state.themes, palette.as_ref());
+    // without a cover to blur, the theme background is kept
+    let (theme, tint) = match (options.background.as_deref(), palette.as_ref()) {
+        (Some("art"), Some(palette)) => {
+            let (theme, tint) = art_theme(palette, &theme);
+            (theme, Some(tint))
+        }
+        _ => (theme, None),
+    };
+
+    let template = playing_template(
+        &state.tera,
+        &state.measurer,
+        &PlayingWidget {
+            layout: options.layout,
+            width: options.width,
+            height: options.height,
+            title: &listen.title,
+            artist: &listen.artist,
+            image: &image,
+            theme: &theme,
+            tint: tint.as_ref(),
+            fill: options.fill.as_ref(),
+            // formats without alpha keep the background so dark mode stays readable
+            transparent: options.transparent.is_some() && options.format.supports_alpha(),
+            listening,
+            mirror: options.mirror.is_some(),
+            wrap: options.wrap,
+            scroll: options.scroll(),
+        },
+    )?;
+
+    render_template(
+        &state.options,
+        template,
+        options.format,
+        options.quality,
+        options.scale,
+    )
+    .await
 }
 
-async fn get_cover_art(
-    metadata: &ListenMetadata,
-    cache: &Cache<String, String>,
-) -> Result<Option<String>, String> {
-    let result = if let Some(release_group) = &metadata.release_group {
-        match cache.get(release_group).await {
-            Some(val) => {
-                info!(
-                    "Cache HIT, getting cover art of release group #{}",
-                    &release_group
-                );
-                Some(val)
-            }
-            None => match cover_art_by_release_group(&release_group).await {
-                Ok(val) => {
-                    info!(
-                        "Cache MISS, inserting cover art of release group #{}",
-                        &release_group
-                    );
-                    cache.insert(release_group.clone(), val.clone()).await;
-                    Some(val)
-                }
-                Err(_err) => None,
-            },
+/// Placeholder art for a listen without cover art, styled by the requested
+/// theme.
+async fn placeholder(
+    state: &AppState,
+    listen: &ListenData,
+    size: u32,
+    options: &RenderOptions,
+) -> Option<CoverArt> {
+    let release = listen
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.release_name.as_deref());
+    let theme = options.theme(&state.themes, None);
+
+    // cached along with covers, as every listen without one draws it
+    let key = format!(
+        "placeholder-{}@{}",
+        placeholder_key(&listen.artist, release, &theme),
+        size
+    );
+    let render = placeholder_art(
+        &state.tera,
+        &state.options,
+        &listen.artist,
+        release,
+        &theme,
+        size,
+    );
+
+    match state.cover_art_cache.try_get_with(key, render).await {
+        Ok(val) => Some(val),
+        Err(err) => {
+            warn!("Cannot render placeholder art: {}", err);
+            None
         }
-    } else if let Some(spotify_path) = &metadata.spotify_path.clone() {
-        match cache.get(spotify_path).await {
-            Some(val) => {
-                info!(
-                    "Cache HIT, getting cover art of Spotify path [{}]",
-                    &spotify_path
-                );
-                Some(val)
-            }
-            None => match cover_art_by_spotify_path(&spotify_path).await {
-                Ok(val) => {
-                    info!(
-                        "Cache MISS, inserting cover art of Spotify path [{}]",
-                        &spotify_path
-                    );
-                    cache.insert(spotify_path.clone(), val.clone()).await;
-                    Some(val)
-                }
-                Err(_err) => None,
-            },
+    }
+}
+
+/// Gets the cover art of a listen from its source, then from each provider
+/// in order until one has it.
+async fn get_cover_art(
+    state: &AppState,
+    source: &dyn ListenSource,
+    listen: &ListenData,
+    size: u32,
+) -> Result<Option<CoverArt>, AoiError> {
+    let chain = &state.cover_art_chain;
+
+    if let Some(cover_art_id) = listen
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.cover_art_id.as_ref())
+    {
+        // ids are only unique within a source
+        let key = format!("{}-{}", source.key(), cover_art_id);
+        let fetch = cached_cover_art(state, key.clone(), size, source.cover_art(cover_art_id));
+
+        if let Some(val) = chain.fetch(key, fetch).await {
+            return Ok(Some(val));
         }
-    } else {
-        None
+    }
+
+    Ok(chain
+        .lookup(listen, |key, provider| {
+            cached_cover_art(state, key, size, provider.cover_art(listen))
+        })
+        .await)
+}
+
+/// Gets cover art scaled to `size` pixels from the cache, scaling the cached
+/// original on a miss and fetching it only when it is missing too. Concurrent
+/// misses on the same key share a single fetch.
+async fn cached_cover_art(
+    state: &AppState,
+    key: String,
+    size: u32,
+    fetch: impl Future<Output = Result<Vec<u8>, AoiError>>,
+) -> Result<CoverArt, AoiError> {
+    let sized_key = format!("{}@{}", key, size);
+    let scale = async {
+        let data = state
+            .cover_art_data
+            .try_get_with(key.clone(), async {
+                info!("Cache MISS, fetching cover art [{}]", &key);
+                fetch.await.map(Arc::from)
+            })
+            .await
+            .map_err(|err| (*err).clone())?;
+
+        CoverArt::scale(data, size).await
     };
 
-    Ok(result)
+    state
+        .cover_art_cache
+        .try_get_with(sized_key, scale)
+        .await
+        .map_err(|err| (*err).clone())
 }
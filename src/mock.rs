@@ -0,0 +1,13 @@
+use axum::Router;
+use tokio::net::TcpListener;
+
+/// Serves `router` on a free local port for the duration of a test, returning
+/// its root URL with a trailing slash.
+pub async fn serve(router: Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+    format!("http://{}/", address)
+}
@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+
+use image::{imageops::FilterType, DynamicImage};
+use serde::Serialize;
+
+use crate::theme::Theme;
+
+/// Name of the theme coloured after the cover art.
+pub const ALBUM_THEME: &str = "album";
+
+/// WCAG contrast for text.
+pub(crate) const TEXT_CONTRAST: f32 = 4.5;
+/// WCAG contrast for text on album backgrounds, pushed to either end so that
+/// it is always reachable.
+const TITLE_CONTRAST: f32 = 7.0;
+/// WCAG contrast for icons.
+const ICON_CONTRAST: f32 = 3.0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub(crate) const BLACK: Color = Color { r: 0, g: 0, b: 0 };
+    pub(crate) const WHITE: Color = Color {
+        r: 255,
+        g: 255,
+        b: 255,
+    };
+
+    /// Parses `#rgb` and `#rrggbb`.
+    pub fn from_hex(hex: &str) -> Option<Color> {
+        let hex = hex.strip_prefix('#')?;
+        let channel = |val: &str| u8::from_str_radix(val, 16).ok();
+
+        match hex.len() {
+            3 => Some(Color {
+                r: channel(&hex[0..1])? * 17,
+                g: channel(&hex[1..2])? * 17,
+                b: channel(&hex[2..3])? * 17,
+            }),
+            6 => Some(Color {
+                r: channel(&hex[0..2])?,
+                g: channel(&hex[2..4])?,
+                b: channel(&hex[4..6])?,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Converts HSL, with the hue in degrees and the rest from 0 to 1.
+    pub fn from_hsl(hue: f32, saturation: f32, lightness: f32) -> Color {
+        let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+        let sector = hue.rem_euclid(360.0) / 60.0;
+        let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+
+        let (r, g, b) = match sector as u32 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        let channel = |val: f32| ((val + lightness - chroma / 2.0) * 255.0).round() as u8;
+
+        Color {
+            r: channel(r),
+            g: channel(g),
+            b: channel(b),
+        }
+    }
+
+    pub fn hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+
+    /// Relative luminance as defined by WCAG.
+    pub fn luminance(&self) -> f32 {
+        let linear = |channel: u8| {
+            let val = channel as f32 / 255.0;
+
+            if val <= 0.04045 {
+                val / 12.92
+            } else {
+                ((val + 0.055) / 1.055).powf(2.4)
+            }
+        };
+
+        0.2126 * linear(self.r) + 0.7152 * linear(self.g) + 0.0722 * linear(self.b)
+    }
+
+    /// WCAG contrast ratio, from 1 to 21.
+    pub fn contrast(&self, other: &Color) -> f32 {
+        let (a, b) = (self.luminance(), other.luminance());
+
+        (a.max(b) + 0.05) / (a.min(b) + 0.05)
+    }
+
+    pub(crate) fn mix(&self, other: Color, amount: f32) -> Color {
+        let channel = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * amount).round() as u8;
+
+        Color {
+            r: channel(self.r, other.r),
+            g: channel(self.g, other.g),
+            b: channel(self.b, other.b),
+        }
+    }
+
+    /// HSL saturation and lightness, from 0 to 1.
+    fn saturation_lightness(&self) -> (f32, f32) {
+        let max = self.r.max(self.g).max(self.b) as f32 / 255.0;
+        let min = self.r.min(self.g).min(self.b) as f32 / 255.0;
+        let lightness = (max + min) / 2.0;
+
+        if max == min {
+            return (0.0, lightness);
+        }
+
+        let saturation = (max - min) / (1.0 - (2.0 * lightness - 1.0).abs());
+
+        (saturation, lightness)
+    }
+
+    /// This colour, darkened or lightened away from `background` until it
+    /// reaches `contrast`. Black or white always reach 4.5.
+    pub(crate) fn readable(&self, background: &Color, contrast: f32) -> Color {
+        let target = match background.contrast(&Color::BLACK) >= background.contrast(&Color::WHITE)
+        {
+            true => Color::BLACK,
+            false => Color::WHITE,
+        };
+
+        (0..=20)
+            .map(|step| self.mix(target, step as f32 / 20.0))
+            .find(|color| color.contrast(background) >= contrast)
+            .unwrap_or(target)
+    }
+}
+
+/// Overlay on the blurred cover drawn as the widget background.
+#[derive(Serialize)]
+pub struct Tint {
+    pub color: String,
+    pub opacity: f32,
+}
+
+/// Colours picked from cover art.
+#[derive(Clone)]
+pub struct Palette {
+    /// Most common colour
+    pub dominant: Color,
+    /// Most saturated colour that is common enough
+    pub vibrant: Color,
+    /// Most common greyish colour
+    pub muted: Color,
+}
+
+impl Palette {
+    /// Computes the palette of an image, `None` when it is fully transparent.
+    pub fn from_image(image: &DynamicImage) -> Option<Palette> {
+        let image = image.resize(64, 64, FilterType::Triangle).into_rgba8();
+
+        // colours are grouped on their 4 high bits per channel
+        let mut buckets: HashMap<(u8, u8, u8), (u32, [u32; 3])> = HashMap::new();
+        let mut total = 0;
+
+        for pixel in image.pixels().filter(|pixel| pixel[3] >= 128) {
+            let [r, g, b, _] = pixel.0;
+            let (count, sum) = buckets.entry((r >> 4, g >> 4, b >> 4)).or_default();
+
+            *count += 1;
+            sum[0] += r as u32;
+            sum[1] += g as u32;
+            sum[2] += b as u32;
+            total += 1;
+        }
+
+        let mut colors: Vec<(u32, Color)> = buckets
+            .into_values()
+            .map(|(count, sum)| {
+                (
+                    count,
+                    Color {
+                        r: (sum[0] / count) as u8,
+                        g: (sum[1] / count) as u8,
+                        b: (sum[2] / count) as u8,
+                    },
+                )
+            })
+            .collect();
+        // ties are broken on the colour so that the palette is stable
+        colors.sort_by_key(|(count, color)| (u32::MAX - count, color.r, color.g, color.b));
+
+        let dominant = colors.first()?.1;
+        // ignore specks, which are mostly noise from scaling
+        let common = colors
+            .iter()
+            .filter(|(count, _)| *count * 200 >= total)
+            .map(|(_, color)| *color);
+
+        let vibrant = common
+            .clone()
+            .map(|color| {
+                let (saturation, lightness) = color.saturation_lightness();
+                (saturation * (1.0 - (2.0 * lightness - 1.0).abs()), color)
+            })
+            .filter(|(score, _)| *score > 0.1)
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, color)| color)
+            .unwrap_or(dominant);
+
+        let muted = common
+            .clone()
+            .find(|color| color.saturation_lightness().0 < 0.3)
+            .unwrap_or(dominant);
+
+        Some(Palette {
+            dominant,
+            vibrant,
+            muted,
+        })
+    }
+}
+
+/// Background the widget is shown on, the page behind it when the theme has
+/// none, guessed from its text colour.
+pub(crate) fn page_background(theme: &Theme) -> Color {
+    let primary = Color::from_hex(&theme.primary).unwrap_or(Color::BLACK);
+
+    theme
+        .background
+        .as_deref()
+        .and_then(Color::from_hex)
+        .unwrap_or(match primary.luminance() < 0.5 {
+            true => Color::WHITE,
+            false => Color::BLACK,
+        })
+}
+
+/// `base` with its artist, label and icon colours taken from the palette,
+/// readable on its background.
+pub fn accent_theme(palette: &Palette, base: &Theme) -> Theme {
+    let background = page_background(base);
+    let accent = palette.vibrant.readable(&background, TEXT_CONTRAST).hex();
+
+    Theme {
+        secondary: accent.clone(),
+        accent: Some(accent),
+        ..base.clone()
+    }
+}
+
+/// Theme coloured after the palette, dark or light following `base`, which
+/// also gives the shadow, radius and font.
+pub fn album_theme(palette: &Palette, base: &Theme) -> Theme {
+    let dark = page_background(base).luminance() < 0.5;
+
+    // the background is pushed towards either end, so that titles reach the
+    // higher contrast
+    let background = match dark {
+        true => (0..=20)
+            .map(|step| palette.dominant.mix(Color::BLACK, step as f32 / 20.0))
+            .find(|color| color.luminance() <= 0.03)
+            .unwrap_or(Color::BLACK),
+        false => (0..=20)
+            .map(|step| palette.dominant.mix(Color::WHITE, step as f32 / 20.0))
+            .find(|color| color.luminance() >= 0.8)
+            .unwrap_or(Color::WHITE),
+    };
+
+    Theme {
+        background: Some(background.hex()),
+        primary: palette.dominant.readable(&background, TITLE_CONTRAST).hex(),
+        secondary: palette.muted.readable(&background, TEXT_CONTRAST).hex(),
+        accent: Some(palette.vibrant.readable(&background, ICON_CONTRAST).hex()),
+        ..base.clone()
+    }
+}
+
+/// `base` drawn on its blurred cover instead of its background, with the cover
+/// darkened under light text, or lightened under dark text for light covers.
+pub fn art_theme(palette: &Palette, base: &Theme) -> (Theme, Tint) {
+    let (color, opacity) = match palette.dominant.luminance() < 0.4 {
+        true => (Color::BLACK, 0.5),
+        false => (Color::WHITE, 0.6),
+    };
+
+    // the blurred cover averages out close to its dominant colour
+    let background = palette.dominant.mix(color, opacity);
+
+    let theme = Theme {
+        background: None,
+        primary: palette.dominant.readable(&background, TITLE_CONTRAST).hex(),
+        secondary: palette.muted.readable(&background, TEXT_CONTRAST).hex(),
+        accent: Some(palette.vibrant.readable(&background, ICON_CONTRAST).hex()),
+        ..base.clone()
+    };
+
+    let tint = Tint {
+        color: color.hex(),
+        opacity,
+    };
+
+    (theme, tint)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    fn hex(hex: &str) -> Color {
+        Color::from_hex(hex).unwrap()
+    }
+
+    #[test]
+    fn parses_hex() {
+        assert_eq!(hex("#abc").hex(), "#aabbcc");
+        assert_eq!(hex("#1A2b3C").hex(), "#1a2b3c");
+        assert!(Color::from_hex("black").is_none());
+        assert!(Color::from_hex("#12345").is_none());
+    }
+
+    #[test]
+    fn black_on_white_contrast() {
+        assert!((Color::BLACK.contrast(&Color::WHITE) - 21.0).abs() < 0.01);
+        assert!((Color::WHITE.contrast(&Color::BLACK) - 21.0).abs() < 0.01);
+        assert!((hex("#777777").contrast(&hex("#777777")) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn readable_colour_is_kept() {
+        assert!(Color::BLACK.readable(&Color::WHITE, TEXT_CONTRAST) == Color::BLACK);
+        assert!(hex("#333333").readable(&Color::WHITE, TEXT_CONTRAST) == hex("#333333"));
+    }
+
+    #[test]
+    fn mid_grey_background_gets_dark_text() {
+        // black contrasts more with mid grey than white does
+        let background = hex("#808080");
+        let color = Color::WHITE.readable(&background, TEXT_CONTRAST);
+
+        assert!(color.contrast(&background) >= TEXT_CONTRAST);
+        assert!(color.luminance() < background.luminance());
+    }
+
+    #[test]
+    fn dark_background_gets_light_text() {
+        let background = hex("#202020");
+        let color = hex("#404040").readable(&background, TEXT_CONTRAST);
+
+        assert!(color.contrast(&background) >= TEXT_CONTRAST);
+        assert!(color.luminance() > background.luminance());
+    }
+
+    #[test]
+    fn palette_of_image() {
+        // mostly grey, with a blue stripe
+        let image = RgbaImage::from_fn(64, 64, |x, _| match x < 16 {
+            true => Rgba([32, 64, 224, 255]),
+            false => Rgba([128, 128, 128, 255]),
+        });
+        let palette = Palette::from_image(&DynamicImage::ImageRgba8(image)).unwrap();
+
+        assert!(palette.dominant == hex("#808080"));
+        assert!(
+            palette.vibrant
+                == Color {
+                    r: 32,
+                    g: 64,
+                    b: 224
+                }
+        );
+        assert!(palette.muted == hex("#808080"));
+    }
+
+    #[test]
+    fn palette_of_transparent_image() {
+        let image = RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 0]));
+
+        assert!(Palette::from_image(&DynamicImage::ImageRgba8(image)).is_none());
+    }
+}
@@ -0,0 +1,140 @@
+use std::sync::Arc;
+
+use tera::Tera;
+use usvg::Options;
+
+use crate::{
+    error::AoiError,
+    palette::{page_background, Color, TEXT_CONTRAST},
+    playing::CoverArt,
+    render::{render_template, ImageFormat},
+    template::placeholder_template,
+    theme::Theme,
+};
+
+/// Cover art for listens without any, a gradient and circles seeded from the
+/// artist and release with the artist initials, the same for every request.
+/// It is dark or light following `theme`, which also gives the font and the
+/// colour of the initials. It has no palette, so `theme=album` and
+/// `background=art` keep the theme as they do without any cover.
+pub async fn placeholder_art(
+    tera: &Tera,
+    opt: &Arc<Options<'static>>,
+    artist: &str,
+    release: Option<&str>,
+    theme: &Theme,
+    size: u32,
+) -> Result<CoverArt, AoiError> {
+    let mut seed = hash(&format!("{}\n{}", artist, release.unwrap_or_default()));
+    let mut next = |max: u32| {
+        // xorshift, enough to spread the bits of the hash
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed % max as u64) as u32
+    };
+
+    let hue = next(360) as f32;
+    let (start, end) = match page_background(theme).luminance() < 0.5 {
+        true => (
+            Color::from_hsl(hue, 0.5, 0.35),
+            Color::from_hsl(hue + 30.0 + next(60) as f32, 0.5, 0.2),
+        ),
+        false => (
+            Color::from_hsl(hue, 0.6, 0.8),
+            Color::from_hsl(hue + 30.0 + next(60) as f32, 0.6, 0.65),
+        ),
+    };
+    let angle = next(360);
+    let circles: Vec<(u32, u32, u32)> = (0..3)
+        .map(|_| (next(100), next(100), 15 + next(30)))
+        .collect();
+
+    let foreground = Color::from_hex(&theme.primary)
+        .unwrap_or(Color::BLACK)
+        .readable(&start.mix(end, 0.5), TEXT_CONTRAST);
+
+    let template = placeholder_template(
+        tera,
+        size,
+        (&start.hex(), &end.hex()),
+        angle,
+        &circles,
+        &initials(artist),
+        &foreground.hex(),
+        &theme.font,
+    )?;
+
+    let data = render_template(opt, template, ImageFormat::Jpeg, Some(85), 1).await?;
+
+    let cover_art = CoverArt::scale(data, size).await?;
+
+    Ok(CoverArt {
+        palette: None,
+        ..cover_art
+    })
+}
+
+/// Identifies the placeholder of `artist` and `release` drawn in `theme`, for
+/// cache keys.
+pub fn placeholder_key(artist: &str, release: Option<&str>, theme: &Theme) -> String {
+    let value = format!(
+        "{}\n{}\n{}\n{}\n{}",
+        artist,
+        release.unwrap_or_default(),
+        theme.background.as_deref().unwrap_or_default(),
+        theme.primary,
+        theme.font
+    );
+
+    format!("{:016x}", hash(&value))
+}
+
+/// First letter of the first two words, `The Beatles` giving `TB`.
+fn initials(name: &str) -> String {
+    name.split_whitespace()
+        .filter_map(|word| word.chars().find(|c| c.is_alphanumeric()))
+        .take(2)
+        .flat_map(|c| c.to_uppercase())
+        .collect()
+}
+
+/// FNV-1a, stable across builds unlike the standard hasher.
+fn hash(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn theme(primary: &str) -> Theme {
+        toml::from_str(&format!(
+            "primary = \"{}\"\nsecondary = \"#888888\"",
+            primary
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn initials_of_first_two_words() {
+        assert_eq!(initials("The Beatles"), "TB");
+        assert_eq!(initials("björk"), "B");
+        assert_eq!(initials("(Sandy) Alex G"), "SA");
+    }
+
+    #[test]
+    fn key_follows_release_and_theme() {
+        let dark = theme("#ffffff");
+        let key = placeholder_key("Artist", Some("Album"), &dark);
+
+        assert_eq!(key, placeholder_key("Artist", Some("Album"), &dark));
+        assert_ne!(key, placeholder_key("Artist", Some("Other"), &dark));
+        assert_ne!(
+            key,
+            placeholder_key("Artist", Some("Album"), &theme("#000000"))
+        );
+    }
+}
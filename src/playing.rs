@@ -1,258 +1,368 @@
+use std::{sync::LazyLock, time::Duration};
+
 use base64::{engine::general_purpose, Engine};
-use listenbrainz::raw::Client;
+use futures::{stream, StreamExt};
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder},
+    imageops::FilterType,
+    ExtendedColorType, ImageEncoder,
+};
 use log::{info, warn};
+use moka::future::Cache;
 use musicbrainz_rs::{
     entity::{release::Release, release_group::ReleaseGroup},
     Browse,
 };
-use regex::Regex;
+
+use crate::{
+    error::AoiError,
+    listenbrainz::{Client, TrackMetadata},
+    palette::Palette,
+};
+
+/// JPEG quality of resized cover art.
+const COVER_QUALITY: u8 = 85;
+
+/// Listens looked up from MusicBrainz at once, which rate limits clients.
+const MUSICBRAINZ_CONCURRENCY: usize = 4;
+
+/// Release ids by recording id, and release group ids by release id. An id
+/// always points to the same entity, so lookups are kept for a day.
+static RELEASES: LazyLock<Cache<String, String>> = LazyLock::new(musicbrainz_cache);
+pub(crate) static RELEASE_GROUPS: LazyLock<Cache<String, String>> =
+    LazyLock::new(musicbrainz_cache);
+
+fn musicbrainz_cache() -> Cache<String, String> {
+    Cache::builder()
+        .time_to_live(Duration::from_secs(24 * 60 * 60))
+        .max_capacity(16 * 1024)
+        .build()
+}
 
 #[derive(Clone)]
 pub struct ListenMetadata {
+    /// MusicBrainz release id
+    pub release: Option<String>,
+    /// Album name, for providers searching by name
+    pub release_name: Option<String>,
+    /// MusicBrainz release group id when the source gives it, otherwise it
+    /// is looked up from the release only if the release has no cover
     pub release_group: Option<String>,
     pub spotify_path: Option<String>,
+    /// Direct cover art URL provided by the listen source
+    pub image_url: Option<String>,
+    /// Cover art served by the listen source itself, see `ListenSource::cover_art`
+    pub cover_art_id: Option<String>,
 }
 
 #[derive(Clone)]
 pub struct ListenData {
     pub title: String,
     pub artist: String,
+    /// Unix timestamp of the listen, `None` while still playing
+    pub listened_at: Option<i64>,
     pub metadata: Option<ListenMetadata>,
 }
 
-pub async fn now_playing(client: &Client, user: &String) -> Result<ListenData, String> {
-    let now_playing = match client.user_playing_now(&user) {
-        Ok(val) => val,
-        Err(err) => return Err(format!("Error while getting user now playing: {}", err)),
-    };
+impl ListenData {
+    /// Key identifying the cover art of this listen, listens sharing it share
+    /// their cover.
+    pub fn cover_art_key(&self) -> Option<String> {
+        let metadata = self.metadata.as_ref()?;
+
+        metadata
+            .cover_art_id
+            .clone()
+            .or(metadata.release.clone())
+            .or(metadata.release_group.clone())
+            .or(metadata.spotify_path.clone())
+            .or(metadata.image_url.clone())
+            .or(metadata
+                .release_name
+                .as_ref()
+                .map(|album| format!("{} - {}", self.artist, album)))
+    }
+}
+
+pub async fn now_playing(client: &Client, user: &str) -> Result<ListenData, AoiError> {
+    let now_playing = client.user_playing_now(user).await?;
 
     let listen = match now_playing.payload.listens.first() {
         Some(val) => val,
-        None => return Err(format!("User [{}] does not have any listen", user)),
-    };
-
-    let title = listen.track_metadata.track_name.clone();
-    let artist = listen.track_metadata.artist_name.clone();
-
-    let release_group =
-        if let Some(release_id_value) = listen.track_metadata.additional_info.get("release_mbid") {
-            if let Some(release_id) = release_id_value.as_str() {
-                if let Ok(release_group) = release_group_by_release(&release_id.to_string()).await {
-                    Some(release_group.id)
-                } else {
-                    warn!("Cannot get release group for release {release_id}");
-                    None
-                }
-            } else {
-                warn!("Cannot parse release ID");
-                None
-            }
-        } else {
-            warn!("Cannot get release for track");
-            None
-        };
-
-    let spotify_path = if let Some(spotify_id_value) = listen
-        .track_metadata
-        .additional_info
-        .get("spotify_album_id")
-    {
-        if let Some(spotify_id) = spotify_id_value.as_str() {
-            Some(
-                spotify_id
-                    .to_string()
-                    .replace("https://open.spotify.com/", ""),
-            )
-        } else {
-            warn!("Cannot Spotify album ID");
-            None
+        None => {
+            return Err(AoiError::NoListens(format!(
+                "User [{}] does not have any listen",
+                user
+            )))
         }
-    } else {
-        None
     };
 
-    Ok(ListenData {
-        title,
-        artist,
-        metadata: Some(ListenMetadata {
-            release_group,
-            spotify_path,
-        }),
-    })
+    Ok(listen_data(&listen.track_metadata, None).await)
 }
 
 pub async fn previous_listens(
     client: &Client,
-    user: &String,
+    user: &str,
     count: u64,
-) -> Result<Vec<ListenData>, String> {
-    let listens = match client.user_listens(&user, None, None, Some(count)) {
-        Ok(val) => val,
-        Err(err) => return Err(format!("Error while getting user listens: {}", err)),
-    };
-
-    Ok(listens
-        .payload
-        .listens
-        .iter()
-        .map(|listen| ListenData {
-            title: listen.track_metadata.track_name.clone(),
-            artist: listen.track_metadata.artist_name.clone(),
-            metadata: None,
-        })
-        .collect())
+) -> Result<Vec<ListenData>, AoiError> {
+    let listens = client.user_listens(user, count).await?;
+
+    // listens keep their order, a few of them being looked up at a time
+    let data =
+        stream::iter(listens.payload.listens)
+            .map(|listen| async move {
+                listen_data(&listen.track_metadata, Some(listen.listened_at)).await
+            })
+            .buffered(MUSICBRAINZ_CONCURRENCY)
+            .collect()
+            .await;
+
+    Ok(data)
 }
 
-pub async fn previous_listen(client: &Client, user: &String) -> Result<ListenData, String> {
-    let listens = match client.user_listens(&user, None, None, Some(1)) {
-        Ok(val) => val,
-        Err(err) => return Err(format!("Error while getting user listens: {}", err)),
+pub async fn previous_listen(client: &Client, user: &str) -> Result<ListenData, AoiError> {
+    let listens = client.user_listens(user, 1).await?;
+
+    let listen = match listens.payload.listens.first() {
+        Some(val) => val,
+        None => {
+            return Err(AoiError::NoListens(format!(
+                "User [{}] does not have any listen",
+                user
+            )))
+        }
     };
 
-    let listen = listens.payload.listens.first().unwrap().clone();
+    Ok(listen_data(&listen.track_metadata, Some(listen.listened_at)).await)
+}
 
-    let title = listen.track_metadata.track_name.clone();
-    let artist = listen.track_metadata.artist_name.clone();
+async fn listen_data(track_metadata: &TrackMetadata, listened_at: Option<i64>) -> ListenData {
+    let title = track_metadata.track_name.clone();
+    let artist = track_metadata.artist_name.clone();
+
+    let mapping = track_metadata.mbid_mapping.as_ref();
+
+    // the release is either mapped by ListenBrainz or submitted by the scrobbler,
+    // otherwise it is looked up from the recording
+    let release_id = match mapping.and_then(|mapping| mapping.release_mbid.clone()) {
+        Some(val) => Some(val),
+        None => match track_metadata
+            .additional_info
+            .get("release_mbid")
+            .and_then(|val| val.as_str())
+        {
+            Some(val) => Some(val.to_string()),
+            None => match mapping.and_then(|mapping| mapping.recording_mbid.as_ref()) {
+                Some(recording_id) => match release_id_by_recording(recording_id).await {
+                    Ok(val) => Some(val),
+                    Err(_) => {
+                        warn!("Cannot get release for recording #{recording_id}");
+                        None
+                    }
+                },
+                None => {
+                    warn!("Cannot get release for track [{title}]");
+                    None
+                }
+            },
+        },
+    };
 
-    let release_group = if let Some(mapping) = listen.track_metadata.mbid_mapping {
-        let recording_id = mapping.recording_mbid;
-        if let Ok(release) = release_by_recording(&recording_id).await {
-            let release_id = release.id;
-            info!("Getting release group of release #{release_id}");
-            if let Ok(release_group) = release_group_by_release(&release_id).await {
-                Some(release_group.id)
+    let spotify_path =
+        if let Some(spotify_id_value) = track_metadata.additional_info.get("spotify_album_id") {
+            if let Some(spotify_id) = spotify_id_value.as_str() {
+                Some(
+                    spotify_id
+                        .to_string()
+                        .replace("https://open.spotify.com/", ""),
+                )
             } else {
-                warn!("Cannot get release group for release #{release_id}");
+                warn!("Cannot Spotify album ID");
                 None
             }
         } else {
-            warn!("Cannot get release for recording #{recording_id}");
             None
-        }
-    } else {
-        warn!("Cannot get release for track [{title}]");
-        None
-    };
-
-    let spotify_path = if let Some(spotify_id_value) = listen
-        .track_metadata
-        .additional_info
-        .get("spotify_album_id")
-    {
-        if let Some(spotify_id) = spotify_id_value.as_str() {
-            Some(
-                spotify_id
-                    .to_string()
-                    .replace("https://open.spotify.com/", ""),
-            )
-        } else {
-            warn!("Cannot Spotify album ID");
-            None
-        }
-    } else {
-        None
-    };
+        };
 
-    Ok(ListenData {
+    ListenData {
         title,
         artist,
+        listened_at,
         metadata: Some(ListenMetadata {
-            release_group,
+            release: release_id,
+            release_name: track_metadata.release_name.clone(),
+            // looked up by the cover art provider when the release has no cover
+            release_group: None,
             spotify_path,
+            image_url: None,
+            cover_art_id: None,
         }),
-    })
+    }
 }
 
-pub async fn cover_art_by_release_group(release_group: &String) -> Result<String, String> {
-    let image = format!(
-        "https://coverartarchive.org/release-group/{}/front-250.jpg",
-        release_group,
-    );
-
-    let response = match reqwest::get(&image).await {
-        Ok(val) => val,
-        Err(err) => return Err(format!("Error while getting image data: {:#?}", err)),
-    };
-
-    let data = match response.bytes().await {
-        Ok(val) => val,
-        Err(err) => return Err(format!("Error while parsing image bytes: {:#?}", err)),
-    };
-
-    let encoded = general_purpose::STANDARD.encode(&data);
-
-    Ok(encoded)
+/// Cover art scaled down for the widget and base64 encoded, cached along with
+/// its palette.
+#[derive(Clone)]
+pub struct CoverArt {
+    pub encoded: String,
+    /// MIME type of the encoded image
+    pub mime: &'static str,
+    pub palette: Option<Palette>,
 }
 
-pub async fn cover_art_by_spotify_path(path: &String) -> Result<String, String> {
-    let url = format!("https://open.spotify.com/embed/{}", path);
-
-    let response = match reqwest::get(&url).await {
-        Ok(val) => val,
-        Err(err) => return Err(format!("Error while getting Spotify data: {:#?}", err)),
-    };
+impl CoverArt {
+    /// Decodes fetched image data, whatever its format, and scales it down to
+    /// fit `size` pixels. Images that are small enough are kept as they are.
+    pub fn new(data: &[u8], size: u32) -> Result<CoverArt, AoiError> {
+        let format = match image::guess_format(data) {
+            Ok(val) => val,
+            Err(err) => {
+                return Err(AoiError::Upstream(format!(
+                    "Cannot detect cover art format: {}",
+                    err
+                )))
+            }
+        };
 
-    let data = match response.text().await {
-        Ok(val) => val,
-        Err(err) => return Err(format!("Error while parsing Spotify data: {:#?}", err)),
-    };
+        let image = match image::load_from_memory_with_format(data, format) {
+            Ok(val) => val,
+            Err(err) => {
+                return Err(AoiError::Upstream(format!(
+                    "Cannot decode cover art: {}",
+                    err
+                )))
+            }
+        };
 
-    let re = Regex::new(r#"(https:\/\/image[\w-]+\.spotifycdn\.com\/image\/[\w\d]+)(\",\"((maxHeight)|(maxWidth))\":300)"#).unwrap();
+        let palette = Palette::from_image(&image);
 
-    let urls: Vec<&str> = re
-        .captures_iter(data.as_str())
-        .map(|c| {
-            let (_, [url, _, _, _]) = c.extract();
-            url
-        })
-        .collect();
+        if image.width().max(image.height()) <= size {
+            return Ok(CoverArt {
+                encoded: general_purpose::STANDARD.encode(data),
+                mime: format.to_mime_type(),
+                palette,
+            });
+        }
 
-    if let Some(url) = urls.first() {
-        let response = match reqwest::get(*url).await {
-            Ok(val) => val,
-            Err(err) => return Err(format!("Error while getting image data: {:#?}", err)),
+        let image = image.resize(size, size, FilterType::CatmullRom);
+        let mut resized = Vec::new();
+
+        // JPEG is much smaller for photos, PNG keeps transparency
+        let transparent =
+            image.color().has_alpha() && image.to_rgba8().pixels().any(|pixel| pixel[3] < u8::MAX);
+        let (result, mime) = match transparent {
+            true => (
+                PngEncoder::new(&mut resized).write_image(
+                    image.to_rgba8().as_raw(),
+                    image.width(),
+                    image.height(),
+                    ExtendedColorType::Rgba8,
+                ),
+                "image/png",
+            ),
+            false => (
+                JpegEncoder::new_with_quality(&mut resized, COVER_QUALITY).write_image(
+                    image.to_rgb8().as_raw(),
+                    image.width(),
+                    image.height(),
+                    ExtendedColorType::Rgb8,
+                ),
+                "image/jpeg",
+            ),
         };
 
-        let data = match response.bytes().await {
-            Ok(val) => val,
-            Err(err) => return Err(format!("Error while parsing image bytes: {:#?}", err)),
-        };
+        match result {
+            Ok(()) => Ok(CoverArt {
+                encoded: general_purpose::STANDARD.encode(&resized),
+                mime,
+                palette,
+            }),
+            Err(err) => Err(AoiError::Render(format!(
+                "Cannot encode cover art: {}",
+                err
+            ))),
+        }
+    }
 
-        let encoded = general_purpose::STANDARD.encode(&data);
+    /// Same as [`CoverArt::new`] on the blocking thread pool, as decoding and
+    /// scaling keep the CPU busy for too long to run on the async workers.
+    pub async fn scale(
+        data: impl AsRef<[u8]> + Send + 'static,
+        size: u32,
+    ) -> Result<CoverArt, AoiError> {
+        match tokio::task::spawn_blocking(move || CoverArt::new(data.as_ref(), size)).await {
+            Ok(val) => val,
+            Err(err) => Err(AoiError::Render(format!("Cover art task failed: {}", err))),
+        }
+    }
 
-        return Ok(encoded);
+    pub fn data_uri(&self) -> String {
+        format!("data:{};base64,{}", self.mime, self.encoded)
     }
+}
+
+/// Id of the first release of a recording, cached.
+pub async fn release_id_by_recording(recording_id: &str) -> Result<String, AoiError> {
+    RELEASES
+        .try_get_with(recording_id.to_string(), async {
+            release_by_recording(recording_id)
+                .await
+                .map(|release| release.id)
+        })
+        .await
+        .map_err(|err| (*err).clone())
+}
 
-    Err("Cannot get image from Spotify".to_string())
+/// Id of the release group of a release, cached.
+pub async fn release_group_id_by_release(release_id: &str) -> Result<String, AoiError> {
+    RELEASE_GROUPS
+        .try_get_with(release_id.to_string(), async {
+            info!("Getting release group of release #{release_id}");
+            release_group_by_release(release_id)
+                .await
+                .map(|release_group| release_group.id)
+        })
+        .await
+        .map_err(|err| (*err).clone())
 }
 
-pub async fn release_by_recording(recording_id: &String) -> Result<Release, String> {
+pub async fn release_by_recording(recording_id: &str) -> Result<Release, AoiError> {
     let results = match Release::browse().by_recording(recording_id).execute().await {
         Ok(val) => val,
-        Err(err) => return Err(format!("Error while browsing release: {}", err)),
+        Err(err) => {
+            return Err(AoiError::Upstream(format!(
+                "Error while browsing release: {}",
+                err
+            )))
+        }
     };
 
     let release = match results.entities.first() {
         Some(val) => val,
-        None => return Err(format!("No release found!")),
+        None => return Err(AoiError::NotFound("No release found!".to_string())),
     };
 
     Ok(release.clone())
 }
 
-pub async fn release_group_by_release(release_id: &String) -> Result<ReleaseGroup, String> {
+pub async fn release_group_by_release(release_id: &str) -> Result<ReleaseGroup, AoiError> {
     let results = match ReleaseGroup::browse()
         .by_release(release_id)
         .execute()
         .await
     {
         Ok(val) => val,
-        Err(err) => return Err(format!("Error while browsing release group: {}", err)),
+        Err(err) => {
+            return Err(AoiError::Upstream(format!(
+                "Error while browsing release group: {}",
+                err
+            )))
+        }
     };
 
     let release_group = match results.entities.first() {
         Some(val) => val,
-        None => return Err(format!("No release group found!")),
+        None => return Err(AoiError::NotFound("No release group found!".to_string())),
     };
 
     Ok(release_group.clone())
@@ -1,33 +1,509 @@
+use std::sync::Arc;
+
+use image::{
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder},
+    ExtendedColorType, ImageEncoder,
+};
+use log::info;
+use resvg::{
+    render,
+    tiny_skia::{Color, Pixmap},
+};
+use rustybuzz::Face;
 use tera::Tera;
-use usvg::{Options, Tree};
+use usvg::{
+    fontdb::{Database, Family, Query, ID},
+    roxmltree, Options, Transform, Tree, WriteOptions,
+};
+
+use crate::{error::AoiError, template::font_template};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Svg,
+    Jpeg,
+    Webp,
+    Avif,
+}
+
+impl ImageFormat {
+    fn from_name(name: &str) -> Option<ImageFormat> {
+        match name.to_lowercase().as_str() {
+            "png" => Some(ImageFormat::Png),
+            "svg" => Some(ImageFormat::Svg),
+            "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+            "webp" => Some(ImageFormat::Webp),
+            "avif" => Some(ImageFormat::Avif),
+            _ => None,
+        }
+    }
+
+    /// Picks the output format from the `format` query parameter, falling back
+    /// to the raster format the `Accept` header gives the highest quality
+    /// value, then PNG. Formats with `q=0` are never picked.
+    pub fn negotiate(format: Option<&String>, accept: Option<&str>) -> ImageFormat {
+        if let Some(format) = format.and_then(|val| ImageFormat::from_name(val)) {
+            return format;
+        }
+
+        // media ranges with their quality, 1 when not given
+        let accepted: Vec<(&str, f32)> = accept
+            .unwrap_or_default()
+            .split(',')
+            .map(|range| {
+                let mut params = range.split(';');
+                let media_type = params.next().unwrap_or_default().trim();
+                let quality = params
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|val| val.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+
+                (media_type, quality)
+            })
+            .collect();
+        let quality = |media_types: &[&str]| {
+            media_types.iter().find_map(|media_type| {
+                accepted
+                    .iter()
+                    .find(|(val, _)| val.eq_ignore_ascii_case(media_type))
+                    .map(|(_, quality)| *quality)
+            })
+        };
+
+        // SVG and JPEG are only served when explicitly requested, as browsers
+        // advertise SVG support and JPEG cannot keep the transparent background.
+        // AVIF and WebP must be listed, PNG also matches wildcards.
+        let candidates = [
+            (ImageFormat::Avif, quality(&["image/avif"])),
+            (ImageFormat::Webp, quality(&["image/webp"])),
+            (ImageFormat::Png, quality(&["image/png", "image/*", "*/*"])),
+        ];
+
+        // on a tie the first candidate, the smallest output, wins
+        candidates
+            .into_iter()
+            .filter_map(|(format, quality)| quality.map(|val| (format, val)))
+            .filter(|(_, quality)| *quality > 0.0)
+            .fold(
+                None,
+                |best: Option<(ImageFormat, f32)>, (format, quality)| match best {
+                    Some((_, val)) if val >= quality => best,
+                    _ => Some((format, quality)),
+                },
+            )
+            .map_or(ImageFormat::Png, |(format, _)| format)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Svg => "svg",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Webp => "webp",
+            ImageFormat::Avif => "avif",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Svg => "image/svg+xml",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Webp => "image/webp",
+            ImageFormat::Avif => "image/avif",
+        }
+    }
+
+    pub fn supports_alpha(&self) -> bool {
+        !matches!(self, ImageFormat::Jpeg)
+    }
+
+    /// Encoding quality from 1 to 100 for lossy formats, `None` for lossless ones.
+    pub fn quality(&self, quality: Option<&String>) -> Option<u8> {
+        let default = match self {
+            ImageFormat::Png | ImageFormat::Svg => return None,
+            ImageFormat::Jpeg => 85,
+            ImageFormat::Webp => 80,
+            ImageFormat::Avif => 60,
+        };
+
+        Some(
+            quality
+                .and_then(|val| val.parse::<u8>().ok())
+                .map(|val| val.clamp(1, 100))
+                .unwrap_or(default),
+        )
+    }
+
+    /// Pixel density of raster output, `2` or `2x` for retina displays.
+    pub fn scale(&self, scale: Option<&String>) -> u8 {
+        if *self == ImageFormat::Svg {
+            return 1;
+        }
+
+        scale
+            .and_then(|val| val.trim_end_matches('x').parse::<u8>().ok())
+            .map(|val| val.clamp(1, MAX_SCALE))
+            .unwrap_or(1)
+    }
+}
+
+pub const MAX_SCALE: u8 = 3;
+
+/// Families tried in order for characters missing from the requested font,
+/// before any other loaded face.
+pub const FALLBACK_FAMILIES: &[&str] =
+    &["Inter", "Noto Sans JP", "Noto Sans KR", "Noto Color Emoji"];
 
-use crate::template::font_template;
+/// Fonts embedded in the binary, loaded in fallback order.
+#[cfg(feature = "bundled-fonts")]
+const BUNDLED_FONTS: &[&[u8]] = &[
+    include_bytes!("../fonts/Inter-Regular.ttf"),
+    include_bytes!("../fonts/Inter-Bold.ttf"),
+    include_bytes!("../fonts/NotoSansJP-Regular.otf"),
+    include_bytes!("../fonts/NotoSansJP-Bold.otf"),
+    include_bytes!("../fonts/NotoSansKR-Regular.otf"),
+    include_bytes!("../fonts/NotoSansKR-Bold.otf"),
+    include_bytes!("../fonts/NotoColorEmoji.ttf"),
+];
+
+/// Builds the options shared by every render and text measurement, so the
+/// font database is only loaded once. Fonts in `font_dir` are loaded on top
+/// of the bundled or system fonts.
+pub fn font_options(font_dir: Option<&str>) -> Options<'static> {
+    let mut opt = Options::default();
+    load_fonts(opt.fontdb_mut());
+
+    if let Some(dir) = font_dir {
+        opt.fontdb_mut().load_fonts_dir(dir);
+    }
+
+    opt.font_family = FALLBACK_FAMILIES[0].to_string();
+    opt.font_resolver.select_fallback = Box::new(|c, used, db| fallback_face(db, used, c));
+
+    info!("Loaded {} font faces", opt.fontdb.len());
+
+    opt
+}
+
+// system fonts are skipped, so the output is the same on every machine
+#[cfg(feature = "bundled-fonts")]
+fn load_fonts(db: &mut Database) {
+    use std::sync::Arc;
+    use usvg::fontdb::Source;
+
+    for font in BUNDLED_FONTS {
+        db.load_font_source(Source::Binary(Arc::new(*font)));
+    }
+
+    db.set_serif_family(FALLBACK_FAMILIES[0]);
+    db.set_sans_serif_family(FALLBACK_FAMILIES[0]);
+}
+
+#[cfg(not(feature = "bundled-fonts"))]
+fn load_fonts(db: &mut Database) {
+    db.load_system_fonts();
+}
+
+/// A face for the character `c`, of the same style as the first used face
+/// and skipping faces already used. [`FALLBACK_FAMILIES`] are tried first,
+/// then every loaded face in load order. Used for both rendering and
+/// measuring, so both pick the same fonts.
+pub fn fallback_face(db: &Database, used: &[ID], c: char) -> Option<ID> {
+    let base_face = db.face(*used.first()?)?;
+
+    let preferred = FALLBACK_FAMILIES.iter().filter_map(|family| {
+        db.query(&Query {
+            families: &[Family::Name(family)],
+            weight: base_face.weight,
+            stretch: base_face.stretch,
+            style: base_face.style,
+        })
+    });
+
+    let loaded = db
+        .faces()
+        .filter(|face| {
+            base_face.style == face.style
+                || base_face.weight == face.weight
+                || base_face.stretch == face.stretch
+        })
+        .map(|face| face.id);
+
+    preferred
+        .chain(loaded)
+        .find(|id| !used.contains(id) && has_char(db, *id, c))
+}
+
+fn has_char(db: &Database, id: ID, c: char) -> bool {
+    db.with_face_data(id, |data, index| {
+        Face::from_slice(data, index).and_then(|face| face.glyph_index(c))
+    })
+    .flatten()
+    .is_some()
+}
 
 pub fn text_width(
     tera: &Tera,
-    content: &String,
+    opt: &Options,
+    content: &str,
     font_family: Option<&str>,
     font_size: Option<i32>,
     font_weight: Option<&str>,
-) -> Result<f32, String> {
-    let template = font_template(&tera, &content, font_family, font_size, font_weight)?;
+) -> Result<f32, AoiError> {
+    let template = font_template(tera, content, font_family, font_size, font_weight)?;
 
-    let mut opt = Options::default();
-    opt.fontdb_mut().load_system_fonts();
-    let tree = match Tree::from_str(template.as_str(), &opt) {
+    let tree = match Tree::from_str(template.as_str(), opt) {
         Ok(val) => val,
         Err(err) => {
-            return Err(format!(
+            return Err(AoiError::Render(format!(
                 "An error occurred while rendering tree: {:#?}",
                 err
-            ))
+            )))
         }
     };
 
     let bounding_box = match tree.root().children().first() {
         Some(val) => val.bounding_box(),
-        _ => return Err("Tree does not contain any children".to_string()),
+        _ => {
+            return Err(AoiError::Render(
+                "Tree does not contain any children".to_string(),
+            ))
+        }
     };
 
     Ok(bounding_box.right() - bounding_box.left())
 }
+
+/// Renders and encodes `template` on the blocking thread pool, as both keep
+/// the CPU busy for too long to run on the async workers.
+pub async fn render_template(
+    opt: &Arc<Options<'static>>,
+    template: String,
+    format: ImageFormat,
+    quality: Option<u8>,
+    scale: u8,
+) -> Result<Vec<u8>, AoiError> {
+    let opt = opt.clone();
+
+    match tokio::task::spawn_blocking(move || {
+        render_blocking(&opt, &template, format, quality, scale)
+    })
+    .await
+    {
+        Ok(val) => val,
+        Err(err) => Err(AoiError::Render(format!("Render task failed: {}", err))),
+    }
+}
+
+fn render_blocking(
+    opt: &Options,
+    template: &str,
+    format: ImageFormat,
+    quality: Option<u8>,
+    scale: u8,
+) -> Result<Vec<u8>, AoiError> {
+    let tree = match Tree::from_data(template.as_bytes(), opt) {
+        Ok(val) => val,
+        Err(err) => {
+            return Err(AoiError::Render(format!(
+                "An error occurred while rendering tree: {:#?}",
+                err
+            )))
+        }
+    };
+
+    // text is converted to paths so the output does not depend on the
+    // fonts installed on the viewer's machine
+    if format == ImageFormat::Svg {
+        let svg = tree.to_string(&WriteOptions::default());
+        return Ok(restore_animations(template, svg).into_bytes());
+    }
+
+    // the layout stays the same, only the pixel density changes
+    let scale = scale as f32;
+    let pixmap_size = match tree.size().to_int_size().scale_by(scale) {
+        Some(val) => val,
+        None => return Err(AoiError::Render("Cannot scale pixmap".to_string())),
+    };
+    let mut pixmap = match Pixmap::new(pixmap_size.width(), pixmap_size.height()) {
+        Some(val) => val,
+        None => return Err(AoiError::Render("Cannot allocate pixmap".to_string())),
+    };
+
+    if !format.supports_alpha() {
+        pixmap.fill(Color::WHITE);
+    }
+
+    render(
+        &tree,
+        Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+
+    encode_pixmap(&pixmap, format, quality)
+}
+
+/// usvg drops SMIL animations when writing, so animations in the template are
+/// copied back into the element with the same id in the written SVG.
+fn restore_animations(template: &str, mut svg: String) -> String {
+    let document = match roxmltree::Document::parse(template) {
+        Ok(val) => val,
+        Err(_) => return svg,
+    };
+
+    for node in document
+        .descendants()
+        .filter(|node| matches!(node.tag_name().name(), "animate" | "animateTransform"))
+    {
+        let id = match node
+            .parent_element()
+            .and_then(|parent| parent.attribute("id"))
+        {
+            Some(val) => val,
+            None => continue,
+        };
+
+        let attributes = node
+            .attributes()
+            .map(|attribute| {
+                let value = attribute
+                    .value()
+                    .replace('&', "&amp;")
+                    .replace('<', "&lt;")
+                    .replace('"', "&quot;");
+
+                format!(" {}=\"{}\"", attribute.name(), value)
+            })
+            .collect::<String>();
+        let animation = format!("<{}{}/>", node.tag_name().name(), attributes);
+
+        if let Some(end) = svg
+            .find(&format!("id=\"{}\"", id))
+            .and_then(|start| svg[start..].find('>').map(|end| start + end + 1))
+        {
+            svg.insert_str(end, &animation);
+        }
+    }
+
+    svg
+}
+
+pub fn encode_pixmap(
+    pixmap: &Pixmap,
+    format: ImageFormat,
+    quality: Option<u8>,
+) -> Result<Vec<u8>, AoiError> {
+    let width = pixmap.width();
+    let height = pixmap.height();
+    let quality = quality.unwrap_or(100);
+    let mut result: Vec<u8> = Vec::new();
+
+    match format {
+        ImageFormat::Svg => {
+            return Err(AoiError::Render(
+                "SVG cannot be encoded from a pixmap".to_string(),
+            ))
+        }
+        ImageFormat::Png => {
+            return match pixmap.encode_png() {
+                Ok(val) => Ok(val),
+                Err(err) => Err(AoiError::Render(format!(
+                    "Error while encoding PNG: {:#?}",
+                    err
+                ))),
+            }
+        }
+        ImageFormat::Webp => {
+            let rgba = straight_rgba(pixmap);
+            let encoded = webp::Encoder::from_rgba(&rgba, width, height).encode(quality as f32);
+            result.extend_from_slice(&encoded);
+        }
+        ImageFormat::Jpeg => {
+            let rgb: Vec<u8> = straight_rgba(pixmap)
+                .chunks_exact(4)
+                .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+                .collect();
+
+            if let Err(err) = JpegEncoder::new_with_quality(&mut result, quality).write_image(
+                &rgb,
+                width,
+                height,
+                ExtendedColorType::Rgb8,
+            ) {
+                return Err(AoiError::Render(format!(
+                    "Error while encoding JPEG: {:#?}",
+                    err
+                )));
+            }
+        }
+        ImageFormat::Avif => {
+            let rgba = straight_rgba(pixmap);
+            if let Err(err) = AvifEncoder::new_with_speed_quality(&mut result, 8, quality)
+                .write_image(&rgba, width, height, ExtendedColorType::Rgba8)
+            {
+                return Err(AoiError::Render(format!(
+                    "Error while encoding AVIF: {:#?}",
+                    err
+                )));
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Pixmap data is premultiplied, while the WebP, JPEG and AVIF encoders
+/// expect straight alpha.
+fn straight_rgba(pixmap: &Pixmap) -> Vec<u8> {
+    pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiate(accept: &str) -> ImageFormat {
+        ImageFormat::negotiate(None, Some(accept))
+    }
+
+    #[test]
+    fn format_parameter_wins() {
+        assert!(
+            ImageFormat::negotiate(Some(&"svg".to_string()), Some("image/avif"))
+                == ImageFormat::Svg
+        );
+    }
+
+    #[test]
+    fn browser_accept_header() {
+        assert!(
+            negotiate("image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8")
+                == ImageFormat::Avif
+        );
+        assert!(negotiate("image/webp,*/*") == ImageFormat::Webp);
+        assert!(negotiate("*/*") == ImageFormat::Png);
+        assert!(negotiate("") == ImageFormat::Png);
+    }
+
+    #[test]
+    fn highest_quality_wins() {
+        assert!(negotiate("image/avif;q=0.5, image/webp;q=0.9") == ImageFormat::Webp);
+        assert!(negotiate("image/avif;q=0.5, image/png") == ImageFormat::Png);
+        assert!(negotiate("image/webp; q=0.8, image/*;q=0.9") == ImageFormat::Png);
+    }
+
+    #[test]
+    fn zero_quality_is_refused() {
+        assert!(negotiate("image/avif;q=0, image/webp") == ImageFormat::Webp);
+        assert!(negotiate("image/avif;q=0, image/webp;q=0.0") == ImageFormat::Png);
+    }
+}
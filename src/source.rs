@@ -0,0 +1,110 @@
+use async_trait::async_trait;
+use std::time::Duration;
+
+use crate::{
+    error::AoiError,
+    listenbrainz::Client,
+    playing::{now_playing, previous_listen, previous_listens, ListenData},
+};
+
+/// A service listens can be fetched from.
+#[async_trait]
+pub trait ListenSource: Send + Sync {
+    /// Identifies the source in cache keys and logs.
+    fn key(&self) -> String;
+
+    /// Human readable name of the service, shown in error widgets.
+    fn name(&self) -> String;
+
+    /// Whether previous listens are available, sources without a history only
+    /// serve the now playing widget.
+    fn has_history(&self) -> bool {
+        true
+    }
+
+    /// Returns the track the user is currently listening to.
+    async fn now_playing(&self, user: &str) -> Result<ListenData, AoiError>;
+
+    /// Returns up to `count` last completed listens, most recent first.
+    async fn previous_listens(&self, user: &str, count: u64) -> Result<Vec<ListenData>, AoiError>;
+
+    /// Returns the image data of cover art for a `ListenMetadata::cover_art_id`
+    /// issued by this source.
+    async fn cover_art(&self, id: &str) -> Result<Vec<u8>, AoiError> {
+        Err(AoiError::NotFound(format!(
+            "[{}] does not provide cover art #{}",
+            self.key(),
+            id
+        )))
+    }
+
+    /// Returns the last completed listen.
+    async fn previous_listen(&self, user: &str) -> Result<ListenData, AoiError> {
+        match self.previous_listens(user, 1).await?.into_iter().next() {
+            Some(val) => Ok(val),
+            None => Err(AoiError::NoListens(format!(
+                "User [{}] does not have any listen",
+                user
+            ))),
+        }
+    }
+}
+
+pub struct ListenBrainzSource {
+    key: String,
+    client: Client,
+}
+
+impl ListenBrainzSource {
+    pub fn new() -> Self {
+        Self {
+            key: "listenbrainz".to_string(),
+            client: Client::new(),
+        }
+    }
+
+    /// Reads from a ListenBrainz-compatible server, `key` must be unique
+    /// among configured servers.
+    pub fn new_with_url(key: impl ToString, url: &str, timeout: Duration) -> Self {
+        // endpoints are appended to the root URL as is
+        let url = if url.ends_with('/') {
+            url.to_string()
+        } else {
+            format!("{}/", url)
+        };
+
+        Self {
+            key: key.to_string(),
+            client: Client::new_with_url(url, timeout),
+        }
+    }
+}
+
+impl Default for ListenBrainzSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl ListenSource for ListenBrainzSource {
+    fn key(&self) -> String {
+        self.key.clone()
+    }
+
+    fn name(&self) -> String {
+        "ListenBrainz".to_string()
+    }
+
+    async fn now_playing(&self, user: &str) -> Result<ListenData, AoiError> {
+        now_playing(&self.client, user).await
+    }
+
+    async fn previous_listens(&self, user: &str, count: u64) -> Result<Vec<ListenData>, AoiError> {
+        previous_listens(&self.client, user, count).await
+    }
+
+    async fn previous_listen(&self, user: &str) -> Result<ListenData, AoiError> {
+        previous_listen(&self.client, user).await
+    }
+}
@@ -0,0 +1,366 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::{
+    error::AoiError,
+    listenbrainz::DEFAULT_TIMEOUT,
+    playing::{ListenData, ListenMetadata},
+    source::ListenSource,
+};
+
+const API_VERSION: &str = "1.16.1";
+const CLIENT_NAME: &str = "aoi";
+
+/// Error code of a missing user, song or cover art.
+const DATA_NOT_FOUND: i32 = 70;
+
+#[derive(Deserialize)]
+struct Response {
+    #[serde(rename = "subsonic-response")]
+    subsonic_response: NowPlayingResponse,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NowPlayingResponse {
+    status: String,
+    error: Option<ResponseError>,
+    now_playing: Option<NowPlaying>,
+}
+
+#[derive(Deserialize)]
+struct ResponseError {
+    code: i32,
+    message: Option<String>,
+}
+
+impl NowPlayingResponse {
+    /// Error of a failed response, missing data being not found.
+    fn error(self) -> AoiError {
+        let message = format!(
+            "Subsonic error: {}",
+            self.error
+                .as_ref()
+                .and_then(|err| err.message.clone())
+                .unwrap_or(self.status)
+        );
+
+        match self.error.map(|err| err.code) {
+            Some(DATA_NOT_FOUND) => AoiError::NotFound(message),
+            _ => AoiError::Upstream(message),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct NowPlaying {
+    #[serde(default)]
+    entry: Vec<NowPlayingEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NowPlayingEntry {
+    username: String,
+    title: String,
+    #[serde(default)]
+    artist: String,
+    album: Option<String>,
+    cover_art: Option<String>,
+}
+
+/// Reads what is currently playing on a Subsonic-compatible server such as Navidrome.
+///
+/// Subsonic does not keep a per-user listen history, so only now playing is available.
+pub struct SubsonicSource {
+    url: String,
+    username: String,
+    password: String,
+    timeout: Duration,
+    http: reqwest::Client,
+}
+
+impl SubsonicSource {
+    pub fn new(url: &str, username: impl ToString, password: impl ToString) -> Self {
+        Self::new_with_timeout(url, username, password, DEFAULT_TIMEOUT)
+    }
+
+    /// Construct a source where every call fails after `timeout`.
+    pub fn new_with_timeout(
+        url: &str,
+        username: impl ToString,
+        password: impl ToString,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            timeout,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Builds a request to an API method, authenticated with a salted token so
+    /// the password is never sent.
+    fn request(&self, method: &str) -> reqwest::RequestBuilder {
+        let salt = format!(
+            "{:x}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|val| val.as_nanos())
+                .unwrap_or_default()
+        );
+        let token = format!("{:x}", md5::compute(format!("{}{}", self.password, salt)));
+
+        self.http
+            .get(format!("{}/rest/{}", self.url, method))
+            .query(&[
+                ("u", self.username.as_str()),
+                ("t", &token),
+                ("s", &salt),
+                ("v", API_VERSION),
+                ("c", CLIENT_NAME),
+                ("f", "json"),
+            ])
+            .timeout(self.timeout)
+    }
+}
+
+#[async_trait]
+impl ListenSource for SubsonicSource {
+    fn key(&self) -> String {
+        "subsonic".to_string()
+    }
+
+    fn name(&self) -> String {
+        "Subsonic".to_string()
+    }
+
+    fn has_history(&self) -> bool {
+        false
+    }
+
+    async fn now_playing(&self, user: &str) -> Result<ListenData, AoiError> {
+        let response = match self
+            .request("getNowPlaying")
+            .send()
+            .await
+            .and_then(|val| val.error_for_status())
+        {
+            Ok(val) => val,
+            Err(err) => {
+                return Err(AoiError::from_reqwest(
+                    "Error while getting Subsonic now playing",
+                    err,
+                ))
+            }
+        };
+
+        let data = match response.json::<Response>().await {
+            Ok(val) => val.subsonic_response,
+            Err(err) => {
+                return Err(AoiError::from_reqwest(
+                    "Error while parsing Subsonic now playing",
+                    err,
+                ))
+            }
+        };
+
+        if data.status != "ok" {
+            return Err(data.error());
+        }
+
+        let entry = match data
+            .now_playing
+            .and_then(|val| val.entry.into_iter().find(|entry| entry.username == user))
+        {
+            Some(val) => val,
+            None => {
+                return Err(AoiError::NoListens(format!(
+                    "User [{}] is not listening to anything",
+                    user
+                )))
+            }
+        };
+
+        Ok(ListenData {
+            title: entry.title,
+            artist: entry.artist,
+            listened_at: None,
+            metadata: Some(ListenMetadata {
+                release: None,
+                release_name: entry.album,
+                release_group: None,
+                spotify_path: None,
+                image_url: None,
+                cover_art_id: entry.cover_art,
+            }),
+        })
+    }
+
+    async fn previous_listens(
+        &self,
+        _user: &str,
+        _count: u64,
+    ) -> Result<Vec<ListenData>, AoiError> {
+        Err(AoiError::NoListens(
+            "Subsonic does not provide listen history".to_string(),
+        ))
+    }
+
+    async fn cover_art(&self, id: &str) -> Result<Vec<u8>, AoiError> {
+        let response = match self
+            .request("getCoverArt")
+            .query(&[("id", id), ("size", "300")])
+            .send()
+            .await
+            .and_then(|val| val.error_for_status())
+        {
+            Ok(val) => val,
+            Err(err) => {
+                return Err(AoiError::from_reqwest(
+                    "Error while getting Subsonic cover art",
+                    err,
+                ))
+            }
+        };
+
+        // errors are answered as a JSON envelope instead of an image
+        let envelope = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|val| val.to_str().ok())
+            .is_some_and(|val| val.starts_with("application/json"));
+
+        if envelope {
+            return match response.json::<Response>().await {
+                Ok(val) => Err(val.subsonic_response.error()),
+                Err(err) => Err(AoiError::from_reqwest(
+                    "Error while parsing Subsonic cover art error",
+                    err,
+                )),
+            };
+        }
+
+        let data = match response.bytes().await {
+            Ok(val) => val,
+            Err(err) => {
+                return Err(AoiError::from_reqwest(
+                    "Error while parsing image bytes",
+                    err,
+                ))
+            }
+        };
+
+        Ok(data.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::{extract::Query, http::header, response::IntoResponse, routing::get, Json, Router};
+    use serde_json::json;
+
+    use super::*;
+    use crate::mock::serve;
+
+    const IMAGE: &[u8] = b"image data";
+
+    /// Answers as a server where `alice` and `bob` are listening, rejecting
+    /// requests that are not signed with the password.
+    async fn source(password: &str) -> SubsonicSource {
+        fn authenticated(query: &HashMap<String, String>) -> bool {
+            let token = format!("{:x}", md5::compute(format!("secret{}", query["s"])));
+
+            query["u"] == "aoi" && query["t"] == token && query["f"] == "json"
+        }
+
+        fn failed(code: i32, message: &str) -> Json<serde_json::Value> {
+            Json(json!({ "subsonic-response": {
+                "status": "failed",
+                "error": { "code": code, "message": message }
+            } }))
+        }
+
+        let router = Router::new()
+            .route(
+                "/rest/getNowPlaying",
+                get(|Query(query): Query<HashMap<String, String>>| async move {
+                    if !authenticated(&query) {
+                        return failed(40, "Wrong username or password");
+                    }
+
+                    Json(json!({ "subsonic-response": {
+                        "status": "ok",
+                        "nowPlaying": { "entry": [
+                            { "username": "alice", "title": "First", "artist": "Artist", "album": "Album", "coverArt": "al-1" },
+                            { "username": "bob", "title": "Second", "artist": "Other" }
+                        ] }
+                    } }))
+                }),
+            )
+            .route(
+                "/rest/getCoverArt",
+                get(|Query(query): Query<HashMap<String, String>>| async move {
+                    match query["id"].as_str() {
+                        "al-1" => ([(header::CONTENT_TYPE, "image/jpeg")], IMAGE).into_response(),
+                        _ => failed(70, "Cover art not found").into_response(),
+                    }
+                }),
+            );
+
+        SubsonicSource::new(&serve(router).await, "aoi", password)
+    }
+
+    #[tokio::test]
+    async fn now_playing_of_user() {
+        let source = source("secret").await;
+
+        let listen = source.now_playing("alice").await.unwrap();
+        assert_eq!(listen.title, "First");
+        assert_eq!(listen.listened_at, None);
+
+        let metadata = listen.metadata.unwrap();
+        assert_eq!(metadata.release_name.as_deref(), Some("Album"));
+        assert_eq!(metadata.cover_art_id.as_deref(), Some("al-1"));
+
+        assert_eq!(source.now_playing("bob").await.unwrap().title, "Second");
+    }
+
+    #[tokio::test]
+    async fn idle_user_has_no_listens() {
+        assert!(matches!(
+            source("secret").await.now_playing("carol").await,
+            Err(AoiError::NoListens(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn wrong_password_is_upstream_error() {
+        assert!(matches!(
+            source("wrong").await.now_playing("alice").await,
+            Err(AoiError::Upstream(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn cover_art() {
+        assert_eq!(
+            source("secret").await.cover_art("al-1").await.unwrap(),
+            IMAGE
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_cover_art_is_not_found() {
+        assert!(matches!(
+            source("secret").await.cover_art("al-2").await,
+            Err(AoiError::NotFound(_))
+        ));
+    }
+}
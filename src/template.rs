@@ -1,18 +1,50 @@
-use std::usize;
-
 use regex::Regex;
+use serde::Serialize;
 use tera::{Context, Tera};
-use unicode_segmentation::UnicodeSegmentation;
 
-use crate::render::text_width;
+use crate::{
+    error::AoiError,
+    layout::{line_height, Layout, LayoutSlots, TextSlot},
+    palette::Tint,
+    playing::ListenData,
+    text::{is_rtl, isolate, TextMeasurer},
+    theme::Theme,
+};
+
+#[derive(Serialize)]
+struct RecentRow {
+    title: String,
+    title_rtl: bool,
+    artist: String,
+    artist_rtl: bool,
+    image: String,
+    listened_at: String,
+}
+
+/// Size of the covers of recent listens.
+pub const RECENT_COVER_SIZE: i32 = 72;
+
+/// Between the title and an inline artist.
+const SEPARATOR: &str = "·";
+
+/// Horizontal scrolling of a line too wide for the widget, in SVG output.
+#[derive(Serialize)]
+struct Marquee {
+    /// Offset of the copy of the line that follows it in
+    distance: f64,
+    /// Seconds per loop
+    duration: f64,
+    /// Fraction of the loop spent paused at the start
+    pause: f64,
+}
 
 pub fn font_template(
     tera: &Tera,
-    content: &String,
+    content: &str,
     font_family: Option<&str>,
     font_size: Option<i32>,
     font_weight: Option<&str>,
-) -> Result<String, String> {
+) -> Result<String, AoiError> {
     let mut context = Context::new();
 
     context.insert("content", content);
@@ -29,112 +61,594 @@ pub fn font_template(
         context.insert("font_weight", font_weight);
     }
 
-    let template = tera.render("font.html", &context).unwrap().to_string();
+    let template = match tera.render("font.html", &context) {
+        Ok(val) => val,
+        Err(err) => {
+            return Err(AoiError::Render(format!(
+                "Error while rendering template: {:#?}",
+                err
+            )))
+        }
+    };
 
     Ok(template)
 }
 
+/// A listen as drawn by the now playing and previous listen widgets.
+pub struct PlayingWidget<'a> {
+    pub layout: Layout,
+    pub width: i32,
+    pub height: i32,
+    pub title: &'a str,
+    pub artist: &'a str,
+    /// Data URI of the cover
+    pub image: &'a str,
+    pub theme: &'a Theme,
+    /// Overlay of the blurred cover drawn behind the widget
+    pub tint: Option<&'a Tint>,
+    pub fill: Option<&'a String>,
+    /// Leaves out the theme background
+    pub transparent: bool,
+    /// Playing now rather than previously listened to
+    pub listening: bool,
+    /// Puts the cover on the right
+    pub mirror: bool,
+    /// Most lines the title wraps to
+    pub wrap: usize,
+    /// Animates text too wide for its slot instead of cutting it
+    pub scroll: bool,
+}
+
+/// Renders the now playing or previous listen widget in its layout, with the
+/// title and artist cut to the text slots of the layout.
 pub fn playing_template(
     tera: &Tera,
+    measurer: &TextMeasurer,
+    widget: &PlayingWidget,
+) -> Result<String, AoiError> {
+    let PlayingWidget {
+        layout,
+        width,
+        height,
+        title,
+        artist,
+        image,
+        theme,
+        tint,
+        fill,
+        transparent,
+        listening,
+        mirror,
+        wrap,
+        scroll,
+    } = *widget;
+
+    let mut context = Context::new();
+    let slots = layout.slots(width, height, &theme.font);
+
+    // scrolling lines are kept whole, on a single line
+    let title_marquee = match scroll && layout.scrolls() {
+        true => marquee(measurer, &slots.title, slots.title.font_sizes[0], title),
+        false => None,
+    };
+
+    let (title_lines, title_size, artist_size) = match title_marquee {
+        Some(_) => (
+            vec![title.to_string()],
+            slots.title.font_sizes[0],
+            slots.artist.font_sizes[0],
+        ),
+        None => title_layout(measurer, layout, &slots, width, height, title, wrap),
+    };
+
+    // an inline artist takes the room left after the title and separator
+    let (separator_x, artist_x) = match slots.inline {
+        true => {
+            let gap = artist_size / 2;
+            let title_width = measurer.width(
+                &title_lines[0],
+                slots.title.font_family,
+                title_size,
+                slots.title.font_weight,
+            );
+            let separator_width = measurer.width(
+                SEPARATOR,
+                slots.artist.font_family,
+                artist_size,
+                slots.artist.font_weight,
+            );
+            let separator_x = title_width.ceil() as i32 + gap;
+
+            (
+                separator_x,
+                separator_x + separator_width.ceil() as i32 + gap,
+            )
+        }
+        false => (0, 0),
+    };
+
+    let artist_marquee = match scroll && layout.scrolls() {
+        true => marquee(measurer, &slots.artist, artist_size, artist),
+        false => None,
+    };
+
+    let artist = match artist_marquee {
+        Some(_) => artist.to_string(),
+        None => text_ellipsis(
+            measurer,
+            slots.artist.width - artist_x,
+            Some(slots.artist.font_family),
+            Some(artist_size),
+            Some(slots.artist.font_weight),
+            artist,
+        )
+        .unwrap_or(artist.to_string()),
+    };
+
+    let title_rtl = is_rtl(title);
+    let artist_rtl = is_rtl(&artist);
+
+    context.insert("width", &width);
+    context.insert("height", &height);
+    context.insert("cover_size", &layout.cover_size(width, height));
+    context.insert("text_width", &slots.title.width);
+    context.insert(
+        "title_lines",
+        &title_lines
+            .iter()
+            .map(|line| isolate(line, title_rtl))
+            .collect::<Vec<String>>(),
+    );
+    context.insert("title_rtl", &title_rtl);
+    context.insert("title_font", slots.title.font_family);
+    context.insert("title_weight", slots.title.font_weight);
+    context.insert("title_size", &title_size);
+    context.insert("title_line_height", &line_height(title_size));
+    context.insert("title_marquee", &title_marquee);
+    context.insert("artist", &isolate(&artist, artist_rtl));
+    context.insert("artist_rtl", &artist_rtl);
+    context.insert("artist_font", slots.artist.font_family);
+    context.insert("artist_weight", slots.artist.font_weight);
+    context.insert("artist_size", &artist_size);
+    context.insert("separator_x", &separator_x);
+    context.insert("artist_x", &artist_x);
+    context.insert("artist_marquee", &artist_marquee);
+    context.insert("separator", SEPARATOR);
+    context.insert("mirror", &mirror);
+    context.insert("image", image);
+    context.insert("listening", &listening);
+    context.insert("tint", &tint);
+
+    insert_theme(&mut context, theme, fill, transparent);
+
+    let template = match tera.render(layout.template(), &context) {
+        Ok(val) => val,
+        Err(err) => {
+            return Err(AoiError::Render(format!(
+                "Error while rendering template: {:#?}",
+                err
+            )))
+        }
+    };
+
+    Ok(template)
+}
+
+/// Renders placeholder cover art, a gradient of `colors` rotated by `angle`
+/// with a few circles and the initials on top.
+#[allow(clippy::too_many_arguments)]
+pub fn placeholder_template(
+    tera: &Tera,
+    size: u32,
+    colors: (&str, &str),
+    angle: u32,
+    circles: &[(u32, u32, u32)],
+    initials: &str,
+    foreground: &str,
+    font: &str,
+) -> Result<String, AoiError> {
+    let mut context = Context::new();
+
+    context.insert("size", &size);
+    context.insert("start", colors.0);
+    context.insert("end", colors.1);
+    context.insert("angle", &angle);
+    context.insert("circles", circles);
+    context.insert("initials", initials);
+    // two initials are set smaller to keep them inside the cover
+    context.insert(
+        "font_size",
+        &match initials.chars().count() {
+            0 | 1 => 44,
+            _ => 36,
+        },
+    );
+    context.insert("foreground", foreground);
+    context.insert("font", font);
+
+    match tera.render("placeholder.html", &context) {
+        Ok(val) => Ok(val),
+        Err(err) => Err(AoiError::Render(format!(
+            "Error while rendering template: {:#?}",
+            err
+        ))),
+    }
+}
+
+/// Renders a vertical list of listens, each paired with the data URI of its
+/// cover art.
+#[allow(clippy::too_many_arguments)]
+pub fn recent_template(
+    tera: &Tera,
+    measurer: &TextMeasurer,
     width: i32,
-    height: i32,
-    title: &String,
-    artist: &String,
-    image_encoded: &String,
-    color_mode: Option<&String>,
+    listens: &[(ListenData, Option<String>)],
+    theme: &Theme,
     fill: Option<&String>,
     transparent: bool,
-    listening: bool,
-) -> Result<String, String> {
+    now: i64,
+) -> Result<String, AoiError> {
+    const BASE_PADDING: i32 = 24;
+    const ROW_HEIGHT: i32 = 96;
+    const TIME_WIDTH: i32 = 200;
+
     let mut context = Context::new();
-    let mut title: String = title.clone();
-    let mut artist: String = artist.clone();
+    let text_x = BASE_PADDING + RECENT_COVER_SIZE + 16;
 
-    if let Ok(ellipsised) = text_ellipsis(
-        &tera,
-        width - height - 24,
-        Some("Inter"),
-        Some(36),
-        Some("700"),
-        &title,
-    ) {
-        title = ellipsised;
-    }
+    let rows: Vec<RecentRow> = listens
+        .iter()
+        .map(|(listen, image)| {
+            let title = text_ellipsis(
+                measurer,
+                width - text_x - BASE_PADDING - TIME_WIDTH,
+                Some(&theme.font),
+                Some(28),
+                Some("700"),
+                &listen.title,
+            )
+            .unwrap_or(listen.title.clone());
+
+            let artist = text_ellipsis(
+                measurer,
+                width - text_x - BASE_PADDING,
+                Some(&theme.font),
+                Some(22),
+                Some("400"),
+                &listen.artist,
+            )
+            .unwrap_or(listen.artist.clone());
+
+            RecentRow {
+                title: isolate(&title, is_rtl(&title)),
+                title_rtl: is_rtl(&title),
+                artist: isolate(&artist, is_rtl(&artist)),
+                artist_rtl: is_rtl(&artist),
+                image: image.clone().unwrap_or_default(),
+                listened_at: match listen.listened_at {
+                    Some(listened_at) => relative_time(listened_at, now),
+                    None => "Now".to_string(),
+                },
+            }
+        })
+        .collect();
 
-    if let Ok(ellipsised) = text_ellipsis(
-        &tera,
+    context.insert("width", &width);
+    context.insert("base_padding", &BASE_PADDING);
+    context.insert("row_height", &ROW_HEIGHT);
+    context.insert("image_size", &RECENT_COVER_SIZE);
+    context.insert("text_x", &text_x);
+    context.insert("time_width", &TIME_WIDTH);
+    context.insert("rows", &rows);
+
+    insert_theme(&mut context, theme, fill, transparent);
+
+    let template = match tera.render("recent.html", &context) {
+        Ok(val) => val,
+        Err(err) => {
+            return Err(AoiError::Render(format!(
+                "Error while rendering template: {:#?}",
+                err
+            )))
+        }
+    };
+
+    Ok(template)
+}
+
+/// Renders a widget-shaped message, used in place of a listen when rendering fails.
+#[allow(clippy::too_many_arguments)]
+pub fn error_template(
+    tera: &Tera,
+    measurer: &TextMeasurer,
+    width: i32,
+    height: i32,
+    title: &str,
+    message: &str,
+    theme: &Theme,
+    fill: Option<&String>,
+    transparent: bool,
+    empty: bool,
+) -> Result<String, AoiError> {
+    let mut context = Context::new();
+
+    let message = text_ellipsis(
+        measurer,
         width - height - 24,
-        Some("Inter"),
+        Some(&theme.font),
         Some(28),
         Some("400"),
-        &artist,
-    ) {
-        artist = ellipsised;
-    }
+        message,
+    )
+    .unwrap_or(message.to_string());
 
     context.insert("width", &width);
     context.insert("height", &height);
-    context.insert("title", &title);
-    context.insert("artist", &artist);
+    context.insert("title", title);
+    context.insert("message", &message);
+    context.insert("empty", &empty);
+
+    insert_theme(&mut context, theme, fill, transparent);
+
+    let template = match tera.render("error.html", &context) {
+        Ok(val) => val,
+        Err(err) => {
+            return Err(AoiError::Render(format!(
+                "Error while rendering template: {:#?}",
+                err
+            )))
+        }
+    };
+
+    Ok(template)
+}
+
+/// Inserts the colours and styling of the theme, with `fill` replacing its
+/// text and icon colours.
+fn insert_theme(context: &mut Context, theme: &Theme, fill: Option<&String>, transparent: bool) {
+    let re = Regex::new(r"\#[abcdefABCDEF\d]{3,6}").unwrap();
+    let fill = fill.filter(|val| re.is_match(val));
+
+    if let Some(background) = theme.background.as_ref().filter(|_| !transparent) {
+        context.insert("background", background);
+    }
+
+    if let Some(fill) = fill {
+        context.insert("fill", fill);
+    }
+
+    context.insert("primary", fill.unwrap_or(&theme.primary));
+    context.insert("secondary", fill.unwrap_or(&theme.secondary));
     context.insert(
-        "image",
-        &format!("data:image/jpeg;base64,{}", image_encoded),
+        "accent",
+        fill.map(|val| val.as_str()).unwrap_or(theme.accent()),
     );
-    context.insert("transparent", &transparent);
-    context.insert("listening", &listening);
+    context.insert("shadow", &theme.shadow);
+    context.insert("shadow_opacity", &theme.shadow_opacity);
+    context.insert("radius", &theme.radius);
+    context.insert("font", &theme.font);
+}
 
-    match color_mode {
-        Some(val) => match val.as_str() {
-            "dark" => context.insert("dark", "dark"),
-            "light" => context.insert("light", "light"),
-            _ => (),
-        },
-        _ => (),
-    }
+/// Picks the title lines and the title and artist font sizes of a layout. A
+/// title that needs more than one line is set smaller, from the largest size
+/// at which it fits in `wrap` lines along with the artist.
+fn title_layout(
+    measurer: &TextMeasurer,
+    layout: Layout,
+    slots: &LayoutSlots,
+    width: i32,
+    height: i32,
+    title: &str,
+    wrap: usize,
+) -> (Vec<String>, i32, i32) {
+    let wrap = match slots.inline {
+        true => 1,
+        false => wrap.max(1),
+    };
 
-    let re = Regex::new(r"\#[abcdefABCDEF\d]{3,6}").unwrap();
+    let mut fallback = None;
+
+    for title_size in slots.title.font_sizes.iter().copied() {
+        let (mut lines, cut) = measurer.wrap(
+            title,
+            slots.title.width as f32,
+            wrap,
+            slots.title.font_family,
+            title_size,
+            slots.title.font_weight,
+        );
+
+        if lines.is_empty() {
+            lines.push(String::new());
+        }
 
-    match fill {
-        Some(val) => {
-            if re.is_match(val) {
-                context.insert("fill", val);
+        // the artist stays smaller than the title
+        let artist_size = slots.artist.font_sizes.iter().copied().find(|artist_size| {
+            *artist_size < title_size
+                && layout.text_fits(width, height, lines.len(), title_size, *artist_size)
+        });
+
+        match artist_size {
+            Some(artist_size) if !cut || wrap <= 1 => return (lines, title_size, artist_size),
+            Some(artist_size) => {
+                fallback.get_or_insert((lines, title_size, artist_size));
             }
+            None => {}
         }
-        _ => (),
     }
 
-    let template = tera.render("widget.html", &context).unwrap().to_string();
+    // nothing fits in full, so the largest layout is ellipsized
+    fallback.unwrap_or_else(|| {
+        let title_size = slots.title.font_sizes[0];
+        let line = measurer.ellipsis(
+            title,
+            slots.title.width as f32,
+            slots.title.font_family,
+            title_size,
+            slots.title.font_weight,
+        );
 
-    Ok(template)
+        (vec![line], title_size, slots.artist.font_sizes[0])
+    })
 }
 
+/// Scrolling for `content` when it is wider than its slot.
+fn marquee(
+    measurer: &TextMeasurer,
+    slot: &TextSlot,
+    font_size: i32,
+    content: &str,
+) -> Option<Marquee> {
+    const GAP: f64 = 64.0;
+    // pixels per second
+    const SPEED: f64 = 48.0;
+    // seconds
+    const PAUSE: f64 = 2.0;
+
+    let content_width = measurer.width(content, slot.font_family, font_size, slot.font_weight);
+
+    if content_width <= slot.width as f32 {
+        return None;
+    }
+
+    let distance = (content_width as f64 + GAP).round();
+    let duration = (distance / SPEED + PAUSE).round();
+
+    Some(Marquee {
+        distance,
+        duration,
+        pause: (PAUSE / duration * 100.0).round() / 100.0,
+    })
+}
+
+/// Formats the time elapsed between two Unix timestamps, e.g. `5 minutes ago`.
+pub fn relative_time(timestamp: i64, now: i64) -> String {
+    let elapsed = (now - timestamp).max(0);
+
+    let (value, unit) = match elapsed {
+        0..60 => return "Just now".to_string(),
+        60..3600 => (elapsed / 60, "minute"),
+        3600..86400 => (elapsed / 3600, "hour"),
+        86400..604800 => (elapsed / 86400, "day"),
+        _ => (elapsed / 604800, "week"),
+    };
+
+    if value == 1 {
+        format!("1 {} ago", unit)
+    } else {
+        format!("{} {}s ago", value, unit)
+    }
+}
+
+/// Cuts `content` to fit in `width`, ending it with an ellipsis when cut.
 pub fn text_ellipsis(
-    tera: &Tera,
+    measurer: &TextMeasurer,
     width: i32,
     font_family: Option<&str>,
     font_size: Option<i32>,
     font_weight: Option<&str>,
-    content: &String,
-) -> Result<String, String> {
-    let graphemes = content.graphemes(true);
-    let size: usize = graphemes.clone().count();
-    let mut end = size;
+    content: &str,
+) -> Result<String, AoiError> {
+    Ok(measurer.ellipsis(
+        content,
+        width as f32,
+        font_family.unwrap_or("Inter"),
+        font_size.unwrap_or(24),
+        font_weight.unwrap_or("normal"),
+    ))
+}
 
-    loop {
-        let mut text: Vec<&str> = graphemes.clone().collect::<Vec<&str>>()[0..end].to_vec();
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
 
-        if text.len() > 0 && end < size {
-            text.push("…");
-        }
+    use super::*;
+    use crate::{
+        render::{font_options, render_template, ImageFormat},
+        text::ELLIPSIS,
+        theme::Themes,
+    };
 
-        let text_width = text_width(&tera, &text.concat(), font_family, font_size, font_weight)
-            .unwrap_or_default();
+    const TITLE: &str = "Everything In Its Right Place (Live at the Ether Festival, London 2000)";
 
-        if text_width > width as f32 {
-            end -= 1;
-            continue;
-        }
+    fn title_lines(layout: Layout, wrap: usize) -> Vec<String> {
+        let measurer = TextMeasurer::new(Arc::new(font_options(None)));
+        let (width, height) = layout.size(None, Some(400));
+        let slots = layout.slots(width, height, "DejaVu Sans");
+
+        title_layout(&measurer, layout, &slots, width, height, TITLE, wrap).0
+    }
+
+    #[test]
+    fn long_title_wraps() {
+        assert_eq!(title_lines(Layout::Horizontal, 1).len(), 1);
+        assert!(title_lines(Layout::Horizontal, 3).len() > 1);
+    }
+
+    #[test]
+    fn inline_title_keeps_one_line() {
+        let lines = title_lines(Layout::Compact, 3);
+
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with(ELLIPSIS));
+    }
+
+    fn playing(layout: Layout, artist: &str, mirror: bool, scroll: bool) -> String {
+        let tera = Tera::new("templates/**/*.html").unwrap();
+        let measurer = TextMeasurer::new(Arc::new(font_options(None)));
+        // measured with a font that is installed where the tests run
+        let theme = Theme {
+            font: "DejaVu Sans".to_string(),
+            ..Themes::load("themes").unwrap().get(None).clone()
+        };
+        let (width, height) = layout.size(None, None);
+
+        playing_template(
+            &tera,
+            &measurer,
+            &PlayingWidget {
+                layout,
+                width,
+                height,
+                title: TITLE,
+                artist,
+                image: "",
+                theme: &theme,
+                tint: None,
+                fill: None,
+                transparent: false,
+                listening: false,
+                mirror,
+                wrap: 1,
+                scroll,
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn mirrored_compact_shows_history_icon() {
+        let svg = playing(Layout::Compact, "Radiohead", true, false);
+
+        assert!(svg.contains("lucide-history"));
+        assert!(!svg.contains("lucide-play"));
+        assert!(svg.contains(r#"text-anchor="end""#));
+    }
+
+    #[tokio::test]
+    async fn scrolling_survives_svg_output() {
+        let svg = playing(Layout::Horizontal, TITLE, false, true);
+        let opt = Arc::new(font_options(None));
+
+        let output = render_template(&opt, svg, ImageFormat::Svg, None, 1)
+            .await
+            .unwrap();
 
-        return Ok(text.concat());
+        assert_eq!(
+            String::from_utf8(output)
+                .unwrap()
+                .matches("<animateTransform")
+                .count(),
+            2
+        );
     }
 }
@@ -0,0 +1,462 @@
+use std::sync::Arc;
+
+use log::warn;
+use moka::sync::Cache;
+use rustybuzz::{Face, UnicodeBuffer};
+use tera::Tera;
+use unicode_bidi::{get_base_direction, BidiInfo, Direction, Level};
+use unicode_linebreak::linebreaks;
+use unicode_segmentation::UnicodeSegmentation;
+use usvg::{
+    fontdb::{Database, Family, Query, Stretch, Style, Weight, ID},
+    Options,
+};
+
+use crate::render::{fallback_face, text_width};
+
+pub const ELLIPSIS: &str = "…";
+
+/// Whether `text` reads right to left, going by its first strong character.
+pub fn is_rtl(text: &str) -> bool {
+    get_base_direction(text) == Direction::Rtl
+}
+
+/// Wraps a line of right-to-left text in an isolate. usvg lays every line out
+/// left to right, so without it a trailing ellipsis or an embedded Latin word
+/// ends up on the wrong side.
+pub fn isolate(text: &str, rtl: bool) -> String {
+    if rtl {
+        format!("\u{2067}{}\u{2069}", text)
+    } else {
+        text.to_string()
+    }
+}
+
+#[derive(Clone, Hash, PartialEq, Eq)]
+struct AdvanceKey {
+    family: String,
+    size: i32,
+    weight: u16,
+    text: String,
+}
+
+/// Measures text by shaping it against the font database, without building
+/// an SVG tree. Advance widths are cached per font, size, weight and text.
+///
+/// Widths are cached for whole strings rather than per grapheme: summing
+/// grapheme advances missed ligatures and letters joining across graphemes,
+/// so every candidate is shaped as a whole to match [`tree_ellipsis`].
+pub struct TextMeasurer {
+    options: Arc<Options<'static>>,
+    advances: Cache<AdvanceKey, f32>,
+}
+
+impl TextMeasurer {
+    pub fn new(options: Arc<Options<'static>>) -> Self {
+        Self {
+            options,
+            // text comes from user input, so the cache is bounded
+            advances: Cache::new(64 * 1024),
+        }
+    }
+
+    /// Width of `content` in pixels, as usvg lays it out.
+    pub fn width(
+        &self,
+        content: &str,
+        font_family: &str,
+        font_size: i32,
+        font_weight: &str,
+    ) -> f32 {
+        self.advance(content, font_family, font_size, font_weight)
+    }
+
+    /// Cuts `content` at a grapheme boundary so it fits in `width`, appending
+    /// an ellipsis when anything was cut. The cut point is found by binary
+    /// search, shaping each candidate with its ellipsis.
+    ///
+    /// Graphemes are cut from the logical end, which is the left side of a
+    /// right-to-left line once it is wrapped with [`isolate`].
+    pub fn ellipsis(
+        &self,
+        content: &str,
+        width: f32,
+        font_family: &str,
+        font_size: i32,
+        font_weight: &str,
+    ) -> String {
+        if self.width(content, font_family, font_size, font_weight) <= width {
+            return content.to_string();
+        }
+
+        let graphemes = content.graphemes(true).collect::<Vec<&str>>();
+        let candidate = |end: usize| format!("{}{}", graphemes[..end].concat(), ELLIPSIS);
+
+        // the longest prefix that still fits once the ellipsis is appended,
+        // an empty prefix is shown without one
+        let end = (1..graphemes.len())
+            .collect::<Vec<usize>>()
+            .partition_point(|end| {
+                self.width(&candidate(*end), font_family, font_size, font_weight) <= width
+            });
+
+        if end == 0 {
+            return String::new();
+        }
+
+        candidate(end)
+    }
+
+    /// Word-wraps `content` into at most `max_lines` lines fitting in `width`,
+    /// breaking where the Unicode line breaking rules allow. Whatever does not
+    /// fit is cut from the last line with an ellipsis, and the returned flag
+    /// tells whether that happened.
+    #[allow(clippy::too_many_arguments)]
+    pub fn wrap(
+        &self,
+        content: &str,
+        width: f32,
+        max_lines: usize,
+        font_family: &str,
+        font_size: i32,
+        font_weight: &str,
+    ) -> (Vec<String>, bool) {
+        // whitespace is collapsed, as it is when the SVG is rendered
+        let content = content.split_whitespace().collect::<Vec<&str>>().join(" ");
+        let mut lines = Vec::new();
+        let mut rest = content.as_str();
+
+        while !rest.is_empty() {
+            if lines.len() + 1 >= max_lines {
+                let line = self.ellipsis(rest, width, font_family, font_size, font_weight);
+                let cut = line != rest;
+                lines.push(line);
+
+                return (lines, cut);
+            }
+
+            let mut end = 0;
+            for (index, _) in linebreaks(rest) {
+                if self.width(
+                    rest[..index].trim_end(),
+                    font_family,
+                    font_size,
+                    font_weight,
+                ) > width
+                {
+                    break;
+                }
+
+                end = index;
+            }
+
+            // a word wider than the line is broken between graphemes
+            if end == 0 {
+                let graphemes = rest.grapheme_indices(true).collect::<Vec<(usize, &str)>>();
+                let count = (1..graphemes.len())
+                    .collect::<Vec<usize>>()
+                    .partition_point(|count| {
+                        self.width(
+                            &rest[..graphemes[*count].0],
+                            font_family,
+                            font_size,
+                            font_weight,
+                        ) <= width
+                    });
+
+                end = graphemes.get(count.max(1)).map_or(rest.len(), |val| val.0);
+            }
+
+            lines.push(rest[..end].trim_end().to_string());
+            rest = rest[end..].trim_start();
+        }
+
+        (lines, false)
+    }
+
+    fn advance(&self, text: &str, font_family: &str, font_size: i32, font_weight: &str) -> f32 {
+        let weight = parse_weight(font_weight);
+        let key = AdvanceKey {
+            family: font_family.to_string(),
+            size: font_size,
+            weight,
+            text: text.to_string(),
+        };
+
+        self.advances.get_with(key, || {
+            shape_advance(&self.options.fontdb, text, font_family, font_size, weight)
+        })
+    }
+}
+
+/// Same as [`TextMeasurer::ellipsis`], building an SVG tree for every
+/// candidate from the full content down. It is the reference the measurer is
+/// tested and benchmarked against, too slow to render with.
+pub fn tree_ellipsis(
+    tera: &Tera,
+    opt: &Options,
+    content: &str,
+    width: f32,
+    font_family: &str,
+    font_size: i32,
+    font_weight: &str,
+) -> String {
+    let graphemes = content.graphemes(true).collect::<Vec<&str>>();
+    let size = graphemes.len();
+    let mut end = size;
+
+    loop {
+        let mut text = graphemes[0..end].to_vec();
+
+        if !text.is_empty() && end < size {
+            text.push(ELLIPSIS);
+        }
+
+        let text_width = text_width(
+            tera,
+            opt,
+            &text.concat(),
+            Some(font_family),
+            Some(font_size),
+            Some(font_weight),
+        )
+        .unwrap_or_default();
+
+        if text_width > width && end > 0 {
+            end -= 1;
+            continue;
+        }
+
+        return text.concat();
+    }
+}
+
+/// Parses a CSS font weight, falling back to normal.
+fn parse_weight(font_weight: &str) -> u16 {
+    match font_weight.trim() {
+        "bold" => 700,
+        "normal" => 400,
+        val => val.parse().unwrap_or(400),
+    }
+}
+
+/// Shapes `text` in bidi runs, picking fonts the same way usvg does when
+/// rendering: the requested families with serif as fallback, then any face of
+/// the same style that has a missing character.
+fn shape_advance(db: &Database, text: &str, font_family: &str, font_size: i32, weight: u16) -> f32 {
+    let names = font_family
+        .split(',')
+        .map(|name| name.trim().trim_matches(|c| c == '"' || c == '\''))
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<&str>>();
+
+    let mut families = names
+        .iter()
+        .map(|name| Family::Name(name))
+        .collect::<Vec<Family>>();
+    families.push(Family::Serif);
+
+    let id = match db.query(&Query {
+        families: &families,
+        weight: Weight(weight),
+        stretch: Stretch::Normal,
+        style: Style::Normal,
+    }) {
+        Some(val) => val,
+        None => {
+            warn!("No match for [{}] font-family", font_family);
+            return 0.0;
+        }
+    };
+
+    let mut glyphs = match shape_with_face(db, id, text) {
+        Some(val) => val,
+        None => return 0.0,
+    };
+
+    // missing glyphs are taken from fallback faces, as usvg does
+    let mut used = vec![id];
+    while let Some(glyph) = glyphs.iter().find(|glyph| glyph.id == 0) {
+        let fallback = match text[glyph.byte_idx..]
+            .chars()
+            .next()
+            .and_then(|c| fallback_face(db, &used, c))
+        {
+            Some(val) => val,
+            None => break,
+        };
+
+        let fallback_glyphs = shape_with_face(db, fallback, text).unwrap_or_default();
+
+        if fallback_glyphs.iter().all(|glyph| glyph.id != 0) {
+            glyphs = fallback_glyphs;
+            break;
+        }
+
+        if fallback_glyphs.len() != glyphs.len() {
+            break;
+        }
+
+        for (glyph, fallback) in glyphs.iter_mut().zip(fallback_glyphs) {
+            if glyph.id == 0 && fallback.id != 0 {
+                *glyph = fallback;
+            }
+        }
+
+        used.push(fallback);
+    }
+
+    // a cluster is as wide as its widest glyph
+    let mut width = 0.0;
+    let mut start = 0;
+    while start < glyphs.len() {
+        let end = glyphs[start..]
+            .iter()
+            .position(|glyph| glyph.byte_idx != glyphs[start].byte_idx)
+            .map_or(glyphs.len(), |val| start + val);
+
+        width += glyphs[start..end]
+            .iter()
+            .map(|glyph| glyph.advance)
+            .fold(0.0, f32::max);
+        start = end;
+    }
+
+    width * font_size as f32
+}
+
+#[derive(Clone)]
+struct Glyph {
+    id: u32,
+    byte_idx: usize,
+    /// Advance in em units
+    advance: f32,
+}
+
+fn shape_with_face(db: &Database, id: ID, text: &str) -> Option<Vec<Glyph>> {
+    db.with_face_data(id, |data, index| {
+        let face = Face::from_slice(data, index)?;
+        let units_per_em = face.units_per_em() as f32;
+
+        // usvg lays text out left to right, shaping each run in its direction
+        let bidi_info = BidiInfo::new(text, Some(Level::ltr()));
+        let paragraph = bidi_info.paragraphs.first()?;
+        let (levels, runs) = bidi_info.visual_runs(paragraph, paragraph.range.clone());
+
+        let mut glyphs = Vec::new();
+        for run in runs.into_iter().filter(|run| !run.is_empty()) {
+            let mut buffer = UnicodeBuffer::new();
+            buffer.push_str(&text[run.clone()]);
+            buffer.set_direction(if levels[run.start].is_ltr() {
+                rustybuzz::Direction::LeftToRight
+            } else {
+                rustybuzz::Direction::RightToLeft
+            });
+
+            let output = rustybuzz::shape(&face, &[], buffer);
+            glyphs.extend(
+                output
+                    .glyph_infos()
+                    .iter()
+                    .zip(output.glyph_positions())
+                    .map(|(info, position)| Glyph {
+                        id: info.glyph_id,
+                        byte_idx: run.start + info.cluster as usize,
+                        advance: position.x_advance as f32 / units_per_em,
+                    }),
+            );
+        }
+
+        Some(glyphs)
+    })?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::font_options;
+
+    // a font that is commonly installed, the tree approach skips text it has no font for
+    const FONT_FAMILY: &str = "DejaVu Sans";
+    const TITLES: [&str; 6] = [
+        "Everything In Its Right Place (Live at the Ether Festival, London 2000)",
+        "Fluffy Office Efficiency Affliction",
+        "The Buffalo Affluent Waffle Shuffle",
+        "AVAVAV Tower Wavy Yacht Lofty",
+        "Ça plaît à l'été, naïve façade",
+        "夜に駆ける Yoru ni Kakeru",
+    ];
+
+    fn measurer() -> TextMeasurer {
+        TextMeasurer::new(Arc::new(font_options(None)))
+    }
+
+    #[test]
+    fn wrap_at_word_boundaries() {
+        let measurer = measurer();
+        let width = measurer.width("Hello world", FONT_FAMILY, 36, "700") + 1.0;
+
+        let (lines, cut) = measurer.wrap("Hello  world again", width, 3, FONT_FAMILY, 36, "700");
+
+        assert_eq!(lines, ["Hello world", "again"]);
+        assert!(!cut);
+    }
+
+    #[test]
+    fn wrap_breaks_long_words() {
+        let measurer = measurer();
+        let word = "Supercalifragilisticexpialidocious";
+        let width = measurer.width("Supercali", FONT_FAMILY, 36, "700") + 1.0;
+
+        let (lines, cut) = measurer.wrap(word, width, 10, FONT_FAMILY, 36, "700");
+
+        assert!(lines.len() > 1);
+        assert_eq!(lines[0], "Supercali");
+        assert_eq!(lines.concat(), word);
+        assert!(lines
+            .iter()
+            .all(|line| measurer.width(line, FONT_FAMILY, 36, "700") <= width));
+        assert!(!cut);
+    }
+
+    #[test]
+    fn wrap_ellipsizes_last_line() {
+        let measurer = measurer();
+        let width = measurer.width("one two", FONT_FAMILY, 36, "700") + 1.0;
+
+        let (lines, cut) = measurer.wrap(
+            "one two three four five six",
+            width,
+            2,
+            FONT_FAMILY,
+            36,
+            "700",
+        );
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "one two");
+        assert!(lines[1].starts_with("three"));
+        assert!(lines[1].ends_with(ELLIPSIS));
+        assert!(measurer.width(&lines[1], FONT_FAMILY, 36, "700") <= width);
+        assert!(cut);
+    }
+
+    #[test]
+    fn ellipsis_matches_tree() {
+        let tera = Tera::new("templates/**/*.html").unwrap();
+        let options = Arc::new(font_options(None));
+        let measurer = TextMeasurer::new(options.clone());
+
+        for title in TITLES {
+            for width in (40..=1400).step_by(60) {
+                assert_eq!(
+                    measurer.ellipsis(title, width as f32, FONT_FAMILY, 36, "700"),
+                    tree_ellipsis(&tera, &options, title, width as f32, FONT_FAMILY, 36, "700"),
+                    "[{}] at {}px",
+                    title,
+                    width
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use log::info;
+use regex::Regex;
+use serde::Deserialize;
+
+/// Theme used when none or an unknown one is requested, it must exist.
+pub const DEFAULT_THEME: &str = "default";
+
+/// Colours and styling of the widgets, read from a TOML file.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Theme {
+    /// Drawn behind the widget, transparent when unset
+    pub background: Option<String>,
+    /// Titles
+    pub primary: String,
+    /// Artists, labels and times
+    pub secondary: String,
+    /// Icons, the secondary colour when unset
+    pub accent: Option<String>,
+    /// Drop shadow of covers
+    #[serde(default = "default_shadow")]
+    pub shadow: String,
+    #[serde(default = "default_shadow_opacity")]
+    pub shadow_opacity: f32,
+    /// Corner radius of the background and covers
+    #[serde(default)]
+    pub radius: i32,
+    #[serde(default = "default_font")]
+    pub font: String,
+}
+
+fn default_shadow() -> String {
+    "#000000".to_string()
+}
+
+fn default_shadow_opacity() -> f32 {
+    0.25
+}
+
+fn default_font() -> String {
+    "Inter".to_string()
+}
+
+impl Theme {
+    pub fn accent(&self) -> &str {
+        self.accent.as_deref().unwrap_or(&self.secondary)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let re = Regex::new(r"^#([[:xdigit:]]{3}|[[:xdigit:]]{6})$").unwrap();
+
+        let colors = [
+            ("background", self.background.as_deref()),
+            ("primary", Some(self.primary.as_str())),
+            ("secondary", Some(self.secondary.as_str())),
+            ("accent", self.accent.as_deref()),
+            ("shadow", Some(self.shadow.as_str())),
+        ];
+
+        for (name, color) in colors {
+            if let Some(color) = color.filter(|color| !re.is_match(color)) {
+                return Err(format!("Invalid {} colour [{}]", name, color));
+            }
+        }
+
+        if !(0.0..=1.0).contains(&self.shadow_opacity) {
+            return Err(format!(
+                "Shadow opacity [{}] is not between 0 and 1",
+                self.shadow_opacity
+            ));
+        }
+
+        if !(0..=64).contains(&self.radius) {
+            return Err(format!("Radius [{}] is not between 0 and 64", self.radius));
+        }
+
+        if self.font.trim().is_empty() {
+            return Err("Font is empty".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Themes {
+    themes: HashMap<String, Theme>,
+}
+
+impl Themes {
+    /// Loads every `{name}.toml` theme of `directory`, failing on the first
+    /// invalid one or when the default theme is missing.
+    pub fn load(directory: &str) -> Result<Themes, String> {
+        let entries = match fs::read_dir(directory) {
+            Ok(val) => val,
+            Err(err) => return Err(format!("Cannot read themes [{}]: {}", directory, err)),
+        };
+
+        let mut themes = HashMap::new();
+
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+
+            if path.extension().and_then(|val| val.to_str()) != Some("toml") {
+                continue;
+            }
+
+            let (name, theme) = load_theme(&path)?;
+            themes.insert(name, theme);
+        }
+
+        if !themes.contains_key(DEFAULT_THEME) {
+            return Err(format!(
+                "Theme [{}] is missing from [{}]",
+                DEFAULT_THEME, directory
+            ));
+        }
+
+        info!("Loaded {} themes", themes.len());
+
+        Ok(Themes { themes })
+    }
+
+    /// The theme of the given name, or the default theme.
+    pub fn get(&self, name: Option<&String>) -> &Theme {
+        name.and_then(|name| self.themes.get(name))
+            .unwrap_or(&self.themes[DEFAULT_THEME])
+    }
+}
+
+fn load_theme(path: &Path) -> Result<(String, Theme), String> {
+    let name = path
+        .file_stem()
+        .and_then(|val| val.to_str())
+        .unwrap_or_default()
+        .to_string();
+
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!("Invalid theme name [{}]", name));
+    }
+
+    let theme = match fs::read_to_string(path) {
+        Ok(val) => match toml::from_str::<Theme>(&val) {
+            Ok(val) => val,
+            Err(err) => return Err(format!("Error while parsing theme [{}]: {}", name, err)),
+        },
+        Err(err) => return Err(format!("Cannot read theme [{}]: {}", name, err)),
+    };
+
+    match theme.validate() {
+        Ok(()) => Ok((name, theme)),
+        Err(err) => Err(format!("Invalid theme [{}]: {}", name, err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// A theme directory holding `themes`, unique to the test.
+    fn directory(test: &str, themes: &[(&str, &str)]) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("aoi-themes-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        for (name, content) in themes {
+            fs::write(directory.join(format!("{}.toml", name)), content).unwrap();
+        }
+
+        directory
+    }
+
+    fn load(test: &str, themes: &[(&str, &str)]) -> Result<Themes, String> {
+        Themes::load(&directory(test, themes).to_string_lossy())
+    }
+
+    const DEFAULT: &str = "primary = \"#000000\"\nsecondary = \"#666666\"";
+
+    #[test]
+    fn bundled_themes_load() {
+        let themes = Themes::load("themes").unwrap();
+
+        assert!(themes.themes.contains_key("dark"));
+        assert!(themes.themes.contains_key("light"));
+    }
+
+    #[test]
+    fn defaults_are_filled_in() {
+        let themes = load("defaults", &[("default", DEFAULT)]).unwrap();
+        let theme = themes.get(None);
+
+        assert_eq!(theme.background, None);
+        assert_eq!(theme.accent(), "#666666");
+        assert_eq!(theme.shadow, "#000000");
+        assert_eq!(theme.shadow_opacity, 0.25);
+        assert_eq!(theme.radius, 0);
+        assert_eq!(theme.font, "Inter");
+    }
+
+    #[test]
+    fn unknown_theme_falls_back_to_default() {
+        let dark = "primary = \"#ffffff\"\nsecondary = \"#aaaaaa\"";
+        let themes = load("fallback", &[("default", DEFAULT), ("dark", dark)]).unwrap();
+
+        assert_eq!(themes.get(Some(&"dark".to_string())).primary, "#ffffff");
+        assert_eq!(themes.get(Some(&"missing".to_string())).primary, "#000000");
+    }
+
+    #[test]
+    fn default_theme_is_required() {
+        assert!(load("required", &[("dark", DEFAULT)]).is_err());
+    }
+
+    #[test]
+    fn bad_themes_are_rejected() {
+        let themes = [
+            ("syntax", "primary = "),
+            ("missing", "primary = \"#000000\""),
+            (
+                "unknown",
+                "primary = \"#000\"\nsecondary = \"#000\"\ncolour = \"#000\"",
+            ),
+            ("colour", "primary = \"black\"\nsecondary = \"#000\""),
+            (
+                "opacity",
+                "primary = \"#000\"\nsecondary = \"#000\"\nshadow_opacity = 2.0",
+            ),
+            (
+                "radius",
+                "primary = \"#000\"\nsecondary = \"#000\"\nradius = 100",
+            ),
+            (
+                "font",
+                "primary = \"#000\"\nsecondary = \"#000\"\nfont = \" \"",
+            ),
+        ];
+
+        for (test, content) in themes {
+            let result = load(test, &[("default", DEFAULT), ("bad", content)]);
+
+            assert!(result.is_err(), "[{}] theme was accepted", test);
+        }
+    }
+
+    #[test]
+    fn bad_theme_names_are_rejected() {
+        assert!(load("name", &[("default", DEFAULT), ("bad name", DEFAULT)]).is_err());
+    }
+}